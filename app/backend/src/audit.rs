@@ -0,0 +1,499 @@
+use axum::{body::HttpBody, response::Response};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::PathBuf,
+    sync::{mpsc, Arc, Mutex},
+    time::SystemTime,
+};
+use tracing::{error, info};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::auth::Identity;
+
+// One line of the audit log. Never carries credentials.
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditEvent {
+    pub timestamp: String,
+    pub action: String,
+    pub user: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub registry: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reference: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub digest: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    pub success: bool,
+    pub status: u16,
+}
+
+impl AuditEvent {
+    pub fn new(action: &str, identity: &Option<Identity>) -> Self {
+        Self {
+            timestamp: String::new(),
+            action: action.to_string(),
+            user: identity
+                .as_ref()
+                .map_or_else(|| "anonymous".to_string(), |i| i.subject.clone()),
+            registry: None,
+            reference: None,
+            version: None,
+            digest: None,
+            size: None,
+            success: false,
+            status: 0,
+        }
+    }
+
+    // Complete the event from the response: outcome, resolved version, size and
+    // the manifest digest handlers attach as an `ArtifactDigest` extension.
+    pub fn finish(mut self, response: &Response) -> Self {
+        let status = response.status();
+        self.status = status.as_u16();
+        self.success = status.is_success();
+        if !self.success {
+            return self;
+        }
+        let headers = response.headers();
+        if let Some(version) = headers.get("X-Resolved-Version").and_then(|v| v.to_str().ok()) {
+            self.version = Some(version.to_string());
+        }
+        self.size = headers
+            .get(http::header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok())
+            .or_else(|| response.body().size_hint().exact());
+        if let Some(ArtifactDigest(digest)) = response.extensions().get() {
+            self.digest = Some(digest.clone());
+        }
+        self
+    }
+
+    fn time(&self) -> Option<SystemTime> {
+        humantime::parse_rfc3339_weak(&self.timestamp).ok()
+    }
+}
+
+// Manifest digest of a served artifact, carried in the response extensions
+#[derive(Clone)]
+pub struct ArtifactDigest(pub String);
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct AuditQuery {
+    /// RFC 3339 bounds, e.g. 2024-05-01T00:00:00Z
+    #[serde(default)]
+    pub since: Option<String>,
+    #[serde(default)]
+    pub until: Option<String>,
+    #[serde(default)]
+    pub user: Option<String>,
+    #[serde(default)]
+    pub action: Option<String>,
+    #[serde(default)]
+    pub limit: Option<usize>,
+}
+
+struct Filter {
+    since: Option<SystemTime>,
+    until: Option<SystemTime>,
+    user: Option<String>,
+    action: Option<String>,
+}
+
+impl Filter {
+    fn matches(&self, event: &AuditEvent) -> bool {
+        if self.user.as_ref().is_some_and(|u| *u != event.user)
+            || self.action.as_ref().is_some_and(|a| *a != event.action)
+        {
+            return false;
+        }
+        if self.since.is_none() && self.until.is_none() {
+            return true;
+        }
+        let Some(time) = event.time() else {
+            return false;
+        };
+        self.since.is_none_or(|since| time >= since) && self.until.is_none_or(|until| time <= until)
+    }
+}
+
+enum Sink {
+    // Recent events are kept in memory so they can still be queried
+    Stdout { recent: VecDeque<AuditEvent>, capacity: usize },
+    File(RotatingFile),
+}
+
+impl Sink {
+    fn write(&mut self, event: AuditEvent) {
+        let line = match serde_json::to_string(&event) {
+            Ok(line) => line,
+            Err(e) => {
+                error!("Failed to serialize audit event: {}", e);
+                return;
+            }
+        };
+        match self {
+            Sink::Stdout { recent, capacity } => {
+                // One write under the stdout lock, so log lines never split an event
+                let mut stdout = std::io::stdout().lock();
+                if let Err(e) = stdout.write_all(format!("{}\n", line).as_bytes()).and_then(|_| stdout.flush()) {
+                    error!("Failed to write audit event: {}", e);
+                }
+                if recent.len() >= *capacity {
+                    recent.pop_front();
+                }
+                recent.push_back(event);
+            }
+            Sink::File(file) => {
+                if let Err(e) = file.write_line(&line) {
+                    error!("Failed to write audit log: {}", e);
+                }
+            }
+        }
+    }
+}
+
+enum Message {
+    Event(AuditEvent),
+    // Answered once every event sent before it is written
+    Flush(mpsc::Sender<()>),
+}
+
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    written: u64,
+    max_bytes: u64,
+    max_files: usize,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_bytes: u64, max_files: usize) -> std::io::Result<Self> {
+        let file = Self::open_append(&path)?;
+        let written = file.metadata()?.len();
+        Ok(Self {
+            path,
+            file,
+            written,
+            max_bytes,
+            max_files,
+        })
+    }
+
+    fn open_append(path: &PathBuf) -> std::io::Result<File> {
+        let mut options = OpenOptions::new();
+        options.create(true).append(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o640);
+        options.open(path)
+    }
+
+    // audit.log.N is the oldest; files beyond max_files are dropped
+    fn rotated(&self, n: usize) -> PathBuf {
+        PathBuf::from(format!("{}.{}", self.path.display(), n))
+    }
+
+    fn write_line(&mut self, line: &str) -> std::io::Result<()> {
+        if self.written > 0 && self.written + line.len() as u64 + 1 > self.max_bytes {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.file.write_all(b"\n")?;
+        self.file.flush()?;
+        self.written += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        for n in (1..self.max_files).rev() {
+            let from = self.rotated(n);
+            if from.exists() {
+                std::fs::rename(&from, self.rotated(n + 1))?;
+            }
+        }
+        if self.max_files > 0 {
+            std::fs::rename(&self.path, self.rotated(1))?;
+        } else {
+            std::fs::remove_file(&self.path)?;
+        }
+        self.file = Self::open_append(&self.path)?;
+        self.written = 0;
+        Ok(())
+    }
+
+    // Current file first, then rotated ones from newest to oldest
+    fn files(&self) -> Vec<PathBuf> {
+        let mut files = vec![self.path.clone()];
+        files.extend((1..=self.max_files).map(|n| self.rotated(n)).filter(|p| p.exists()));
+        files
+    }
+}
+
+// Events are written by a dedicated thread, so requests never wait on the
+// file or stdout
+pub struct AuditLog {
+    sink: Arc<Mutex<Sink>>,
+    sender: mpsc::Sender<Message>,
+}
+
+impl AuditLog {
+    // AUDIT_LOG: `stdout` (default) or a file path, rotated after AUDIT_LOG_MAX_BYTES
+    // (default 100 MiB) keeping AUDIT_LOG_MAX_FILES old files (default 5).
+    pub fn from_env() -> anyhow::Result<Self> {
+        let target = std::env::var("AUDIT_LOG").unwrap_or_default();
+        let sink = match target.as_str() {
+            "" | "stdout" => {
+                let capacity = env_number("AUDIT_BUFFER_SIZE", 10_000);
+                info!("Audit log: stdout (last {} events queryable)", capacity);
+                Sink::Stdout {
+                    recent: VecDeque::with_capacity(capacity.min(1024)),
+                    capacity,
+                }
+            }
+            path => {
+                let max_bytes = env_number("AUDIT_LOG_MAX_BYTES", 100 * 1024 * 1024) as u64;
+                let max_files = env_number("AUDIT_LOG_MAX_FILES", 5);
+                let file = RotatingFile::open(PathBuf::from(path), max_bytes, max_files)
+                    .map_err(|e| anyhow::anyhow!("Failed to open audit log {}: {}", path, e))?;
+                info!("Audit log: {}", path);
+                Sink::File(file)
+            }
+        };
+        Self::new(sink).map_err(|e| anyhow::anyhow!("Failed to start the audit writer: {}", e))
+    }
+
+    fn new(sink: Sink) -> std::io::Result<Self> {
+        let sink = Arc::new(Mutex::new(sink));
+        let (sender, receiver) = mpsc::channel();
+        let writer = sink.clone();
+        std::thread::Builder::new().name("audit-writer".to_string()).spawn(move || {
+            for message in receiver {
+                match message {
+                    Message::Event(event) => writer.lock().unwrap().write(event),
+                    Message::Flush(done) => {
+                        let _ = done.send(());
+                    }
+                }
+            }
+        })?;
+        Ok(Self { sink, sender })
+    }
+
+    pub fn record(&self, mut event: AuditEvent) {
+        event.timestamp = humantime::format_rfc3339_millis(SystemTime::now()).to_string();
+        if self.sender.send(Message::Event(event)).is_err() {
+            error!("Audit writer stopped, event dropped");
+        }
+    }
+
+    // Wait until the events recorded so far are written
+    pub fn flush(&self) {
+        let (done, wait) = mpsc::channel();
+        if self.sender.send(Message::Flush(done)).is_ok() {
+            let _ = wait.recv();
+        }
+    }
+
+    // Matching events, newest first
+    pub fn query(&self, query: &AuditQuery) -> Result<Vec<AuditEvent>, String> {
+        let parse = |v: &Option<String>| -> Result<Option<SystemTime>, String> {
+            v.as_deref()
+                .filter(|v| !v.is_empty())
+                .map(|v| humantime::parse_rfc3339_weak(v).map_err(|_| format!("Invalid timestamp: {}", v)))
+                .transpose()
+        };
+        let filter = Filter {
+            since: parse(&query.since)?,
+            until: parse(&query.until)?,
+            user: query.user.clone().filter(|u| !u.is_empty()),
+            action: query.action.clone().filter(|a| !a.is_empty()),
+        };
+        let limit = query.limit.unwrap_or(100).clamp(1, 1000);
+
+        let files = match &*self.sink.lock().unwrap() {
+            Sink::Stdout { recent, .. } => {
+                return Ok(recent
+                    .iter()
+                    .rev()
+                    .filter(|e| filter.matches(e))
+                    .take(limit)
+                    .cloned()
+                    .collect());
+            }
+            Sink::File(file) => file.files(),
+        };
+
+        let mut events = Vec::new();
+        for path in files {
+            let Ok(file) = File::open(&path) else {
+                continue;
+            };
+            let mut matching: Vec<AuditEvent> = BufReader::new(file)
+                .lines()
+                .map_while(Result::ok)
+                .filter_map(|line| serde_json::from_str(&line).ok())
+                .filter(|e| filter.matches(e))
+                .collect();
+            matching.reverse();
+            events.extend(matching);
+            if events.len() >= limit {
+                break;
+            }
+        }
+        events.truncate(limit);
+        Ok(events)
+    }
+}
+
+fn env_number(var: &str, default: usize) -> usize {
+    std::env::var(var)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn lines(path: &Path) -> Vec<String> {
+        std::fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(str::to_string)
+            .collect()
+    }
+
+    fn event(action: &str, user: &str, timestamp: &str) -> AuditEvent {
+        AuditEvent {
+            timestamp: timestamp.to_string(),
+            user: user.to_string(),
+            ..AuditEvent::new(action, &None)
+        }
+    }
+
+    fn time(v: &str) -> Option<SystemTime> {
+        Some(humantime::parse_rfc3339_weak(v).unwrap())
+    }
+
+    #[test]
+    fn rotation_keeps_max_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        // Two 7-byte lines per file
+        let mut file = RotatingFile::open(path.clone(), 14, 2).unwrap();
+        for n in 0..7 {
+            file.write_line(&format!("line-{}", n)).unwrap();
+        }
+        assert_eq!(lines(&path), ["line-6"]);
+        assert_eq!(lines(&file.rotated(1)), ["line-4", "line-5"]);
+        assert_eq!(lines(&file.rotated(2)), ["line-2", "line-3"]);
+        assert!(!file.rotated(3).exists());
+        assert_eq!(file.files(), [path.clone(), file.rotated(1), file.rotated(2)]);
+
+        // Reopening continues from the current size
+        let mut file = RotatingFile::open(path.clone(), 14, 2).unwrap();
+        file.write_line("line-7").unwrap();
+        assert_eq!(lines(&path), ["line-6", "line-7"]);
+    }
+
+    #[test]
+    fn rotation_without_kept_files_truncates() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        let mut file = RotatingFile::open(path.clone(), 14, 0).unwrap();
+        for n in 0..3 {
+            file.write_line(&format!("line-{}", n)).unwrap();
+        }
+        assert_eq!(lines(&path), ["line-2"]);
+        assert!(!file.rotated(1).exists());
+        assert_eq!(file.files(), [path]);
+    }
+
+    #[test]
+    fn filter_bounds_are_inclusive() {
+        let filter = Filter {
+            since: time("2024-05-01T00:00:00Z"),
+            until: time("2024-05-02T00:00:00Z"),
+            user: None,
+            action: None,
+        };
+        assert!(filter.matches(&event("pull", "alice", "2024-05-01T00:00:00Z")));
+        assert!(filter.matches(&event("pull", "alice", "2024-05-01T12:00:00.250Z")));
+        assert!(filter.matches(&event("pull", "alice", "2024-05-02T00:00:00Z")));
+        assert!(!filter.matches(&event("pull", "alice", "2024-04-30T23:59:59.999Z")));
+        assert!(!filter.matches(&event("pull", "alice", "2024-05-02T00:00:00.001Z")));
+        // Without a readable time an event cannot be placed within the bounds
+        assert!(!filter.matches(&event("pull", "alice", "")));
+
+        let since = Filter {
+            until: None,
+            ..filter
+        };
+        assert!(since.matches(&event("pull", "alice", "2030-01-01T00:00:00Z")));
+        let user = Filter {
+            since: None,
+            until: None,
+            user: Some("alice".to_string()),
+            action: Some("pull".to_string()),
+        };
+        assert!(user.matches(&event("pull", "alice", "")));
+        assert!(!user.matches(&event("pull", "bob", "")));
+        assert!(!user.matches(&event("pullChart", "alice", "")));
+    }
+
+    #[test]
+    fn query_is_newest_first_across_rotated_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        // Room for about two events per file
+        let audit = AuditLog::new(Sink::File(RotatingFile::open(path.clone(), 200, 5).unwrap())).unwrap();
+        for n in 0..6 {
+            let user = if n % 2 == 0 { "alice" } else { "bob" };
+            audit.record(event(&format!("pull-{}", n), user, ""));
+        }
+        audit.flush();
+        assert!(PathBuf::from(format!("{}.2", path.display())).exists());
+
+        let actions = |query: AuditQuery| -> Vec<String> {
+            audit.query(&query).unwrap().into_iter().map(|e| e.action).collect()
+        };
+        let query = || AuditQuery {
+            since: None,
+            until: None,
+            user: None,
+            action: None,
+            limit: None,
+        };
+        assert_eq!(actions(query()), ["pull-5", "pull-4", "pull-3", "pull-2", "pull-1", "pull-0"]);
+        assert_eq!(
+            actions(AuditQuery {
+                limit: Some(3),
+                ..query()
+            }),
+            ["pull-5", "pull-4", "pull-3"]
+        );
+        assert_eq!(
+            actions(AuditQuery {
+                user: Some("alice".to_string()),
+                ..query()
+            }),
+            ["pull-4", "pull-2", "pull-0"]
+        );
+        assert!(audit
+            .query(&AuditQuery {
+                since: Some("yesterday".to_string()),
+                ..query()
+            })
+            .is_err());
+    }
+}
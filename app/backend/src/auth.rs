@@ -0,0 +1,516 @@
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderMap},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use jsonwebtoken::{jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::{
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+use tracing::{debug, info, info_span, warn, Instrument};
+
+use crate::error::ApiError;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum AuthMethod {
+    ApiKey,
+    Jwt,
+}
+
+// The caller behind a request, inserted into the request extensions by `authenticate`
+#[derive(Clone, Debug)]
+pub struct Identity {
+    pub subject: String,
+    pub method: AuthMethod,
+    pub roles: Vec<String>,
+}
+
+impl Identity {
+    pub fn has_role(&self, role: &str) -> bool {
+        self.roles.iter().any(|r| r == role)
+    }
+}
+
+// Entry of the API key file. Only the SHA-256 of the key is stored.
+#[derive(Deserialize)]
+struct ApiKey {
+    id: String,
+    sha256: String,
+    #[serde(default)]
+    roles: Vec<String>,
+}
+
+pub enum JwksSource {
+    Url(String),
+    File(PathBuf),
+}
+
+pub struct OidcConfig {
+    pub issuer: String,
+    pub audience: Option<String>,
+    pub jwks: JwksSource,
+    // Claim holding the caller roles (string or list of strings)
+    pub roles_claim: String,
+    // Minimum delay between two JWKS refreshes triggered by unknown key ids
+    pub refresh_interval: Duration,
+}
+
+struct Oidc {
+    config: OidcConfig,
+    keys: RwLock<JwkSet>,
+    last_refresh: RwLock<Instant>,
+    client: reqwest::Client,
+}
+
+pub struct Authenticator {
+    api_keys: Vec<ApiKey>,
+    oidc: Option<Oidc>,
+    // Role required by the /api/admin routes
+    admin_role: String,
+}
+
+impl Authenticator {
+    // `auth` section: an API key file with {"id", "sha256", "roles"} entries
+    // and/or an OIDC issuer whose JWKS is fetched from a URL or read from a file.
+    pub async fn from_config(auth: &crate::config::Auth, jwks_refresh: Duration) -> anyhow::Result<Self> {
+        let api_keys = match &auth.api_keys_file {
+            Some(path) => {
+                let text = tokio::fs::read_to_string(path)
+                    .await
+                    .map_err(|e| anyhow::anyhow!("Failed to read API keys file {}: {}", path.display(), e))?;
+                let keys: Vec<ApiKey> = serde_json::from_str(&text)
+                    .map_err(|e| anyhow::anyhow!("Invalid API keys file {}: {}", path.display(), e))?;
+                for key in &keys {
+                    if key.sha256.len() != 64 || hex::decode(&key.sha256).is_err() {
+                        anyhow::bail!("API key {} must have a hex-encoded SHA-256 hash", key.id);
+                    }
+                }
+                info!("Loaded {} API keys", keys.len());
+                keys
+            }
+            None => Vec::new(),
+        };
+
+        let oidc = match &auth.oidc {
+            Some(oidc) => {
+                let jwks = match (&oidc.jwks_url, &oidc.jwks_file) {
+                    (Some(url), _) => JwksSource::Url(url.clone()),
+                    (None, Some(file)) => JwksSource::File(file.clone()),
+                    (None, None) => anyhow::bail!("OIDC issuer requires a JWKS URL or file"),
+                };
+                let config = OidcConfig {
+                    issuer: oidc.issuer.clone(),
+                    audience: oidc.audience.clone(),
+                    jwks,
+                    roles_claim: oidc.roles_claim.clone().unwrap_or_else(|| "groups".to_string()),
+                    refresh_interval: jwks_refresh,
+                };
+                Some(Oidc::new(config).await?)
+            }
+            None => None,
+        };
+
+        let admin_role = auth.admin_role.clone();
+
+        Ok(Self {
+            api_keys,
+            oidc,
+            admin_role,
+        })
+    }
+
+    pub fn enabled(&self) -> bool {
+        !self.api_keys.is_empty() || self.oidc.is_some()
+    }
+
+    async fn identify(&self, headers: &HeaderMap) -> Result<Identity, &'static str> {
+        if let Some(key) = headers.get("x-api-key").and_then(|v| v.to_str().ok()) {
+            return self.check_api_key(key).ok_or("Invalid API key");
+        }
+
+        let Some(token) = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .map(str::trim)
+        else {
+            return Err("Missing credentials");
+        };
+
+        if let Some(identity) = self.check_api_key(token) {
+            return Ok(identity);
+        }
+        match &self.oidc {
+            Some(oidc) if token.split('.').count() == 3 => oidc.validate(token).await,
+            _ => Err("Invalid bearer token"),
+        }
+    }
+
+    fn check_api_key(&self, key: &str) -> Option<Identity> {
+        let hash = hex::encode(Sha256::digest(key.as_bytes()));
+        self.api_keys
+            .iter()
+            .find(|k| constant_time_eq(k.sha256.to_lowercase().as_bytes(), hash.as_bytes()))
+            .map(|k| Identity {
+                subject: k.id.clone(),
+                method: AuthMethod::ApiKey,
+                roles: k.roles.clone(),
+            })
+    }
+}
+
+impl Oidc {
+    async fn new(config: OidcConfig) -> anyhow::Result<Self> {
+        let client = reqwest::Client::builder()
+            .user_agent("tessark-backend/0.1")
+            .timeout(Duration::from_secs(10))
+            .build()?;
+        let keys = load_jwks(&client, &config.jwks).await?;
+        info!("Loaded {} OIDC signing keys for {}", keys.keys.len(), config.issuer);
+        Ok(Self {
+            config,
+            keys: RwLock::new(keys),
+            last_refresh: RwLock::new(Instant::now()),
+            client,
+        })
+    }
+
+    async fn validate(&self, token: &str) -> Result<Identity, &'static str> {
+        let header = jsonwebtoken::decode_header(token).map_err(|_| "Malformed token")?;
+        // Only asymmetric algorithms: the JWKS never holds shared secrets
+        if matches!(header.alg, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512) {
+            return Err("Unsupported token algorithm");
+        }
+
+        let key = match self.decoding_key(header.kid.as_deref()) {
+            Some(key) => key,
+            None => {
+                self.refresh().await;
+                self.decoding_key(header.kid.as_deref())
+                    .ok_or("Unknown signing key")?
+            }
+        };
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&self.config.issuer]);
+        match &self.config.audience {
+            Some(aud) => validation.set_audience(&[aud]),
+            None => validation.validate_aud = false,
+        }
+
+        let data = jsonwebtoken::decode::<serde_json::Value>(token, &key, &validation).map_err(|e| {
+            debug!("JWT rejected: {}", e);
+            "Invalid token"
+        })?;
+
+        let subject = data
+            .claims
+            .get("sub")
+            .and_then(|v| v.as_str())
+            .ok_or("Token without subject")?
+            .to_string();
+        let roles = match data.claims.get(&self.config.roles_claim) {
+            Some(serde_json::Value::String(role)) => vec![role.clone()],
+            Some(serde_json::Value::Array(roles)) => roles
+                .iter()
+                .filter_map(|r| r.as_str().map(str::to_string))
+                .collect(),
+            _ => Vec::new(),
+        };
+        Ok(Identity {
+            subject,
+            method: AuthMethod::Jwt,
+            roles,
+        })
+    }
+
+    fn decoding_key(&self, kid: Option<&str>) -> Option<DecodingKey> {
+        let keys = self.keys.read().unwrap();
+        let jwk = match kid {
+            Some(kid) => keys.find(kid)?,
+            None if keys.keys.len() == 1 => &keys.keys[0],
+            None => return None,
+        };
+        DecodingKey::from_jwk(jwk).ok()
+    }
+
+    // Reload the key set, e.g. after the issuer rotated its keys
+    async fn refresh(&self) {
+        if self.last_refresh.read().unwrap().elapsed() < self.config.refresh_interval {
+            return;
+        }
+        *self.last_refresh.write().unwrap() = Instant::now();
+        match load_jwks(&self.client, &self.config.jwks).await {
+            Ok(keys) => {
+                debug!("Refreshed OIDC signing keys ({} keys)", keys.keys.len());
+                *self.keys.write().unwrap() = keys;
+            }
+            Err(e) => warn!("Failed to refresh OIDC signing keys: {}", e),
+        }
+    }
+}
+
+async fn load_jwks(client: &reqwest::Client, source: &JwksSource) -> anyhow::Result<JwkSet> {
+    let text = match source {
+        JwksSource::File(path) => tokio::fs::read_to_string(path)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to read JWKS file {}: {}", path.display(), e))?,
+        JwksSource::Url(url) => {
+            let response = client
+                .get(url)
+                .send()
+                .instrument(info_span!("oidc.jwks", otel.kind = "client", url = %url))
+                .await?;
+            if !response.status().is_success() {
+                anyhow::bail!("JWKS endpoint {} returned {}", url, response.status());
+            }
+            response.text().await?
+        }
+    };
+    serde_json::from_str(&text).map_err(|e| anyhow::anyhow!("Invalid JWKS: {}", e))
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// Middleware resolving the caller identity. When no authentication method is
+// configured every request goes through anonymously.
+pub async fn authenticate(
+    State(auth): State<Arc<Authenticator>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    if !auth.enabled() {
+        return next.run(request).await;
+    }
+
+    match auth.identify(request.headers()).await {
+        Ok(identity) => {
+            debug!("Authenticated {} via {:?}", identity.subject, identity.method);
+            request.extensions_mut().insert(identity);
+            next.run(request).await
+        }
+        Err(reason) => {
+            warn!("Authentication failed for {}: {}", request.uri().path(), reason);
+            ApiError::Unauthenticated(reason.to_string()).into_response()
+        }
+    }
+}
+
+// Guard for admin routes, layered inside `authenticate`. Without authentication
+// there is no caller to check, so admin routes stay closed.
+pub async fn require_admin(
+    State(auth): State<Arc<Authenticator>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let allowed = request
+        .extensions()
+        .get::<Identity>()
+        .is_some_and(|identity| identity.has_role(&auth.admin_role));
+    if !allowed {
+        warn!("Admin access denied for {}", request.uri().path());
+        return ApiError::Forbidden("Admin role required".to_string()).into_response();
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, http::StatusCode, middleware, routing::get, Router};
+    use base64::Engine;
+    use jsonwebtoken::{EncodingKey, Header};
+    use std::path::Path;
+    use tower::ServiceExt;
+
+    const ISSUER: &str = "https://issuer.example";
+    const AUDIENCE: &str = "tessark";
+
+    fn api_key(id: &str, key: &str, roles: &[&str]) -> ApiKey {
+        ApiKey {
+            id: id.to_string(),
+            sha256: hex::encode(Sha256::digest(key.as_bytes())),
+            roles: roles.iter().map(|r| r.to_string()).collect(),
+        }
+    }
+
+    fn authenticator(api_keys: Vec<ApiKey>, oidc: Option<Oidc>) -> Authenticator {
+        Authenticator {
+            api_keys,
+            oidc,
+            admin_role: "admin".to_string(),
+        }
+    }
+
+    async fn oidc(jwks: &Path, refresh_interval: Duration) -> Oidc {
+        Oidc::new(OidcConfig {
+            issuer: ISSUER.to_string(),
+            audience: Some(AUDIENCE.to_string()),
+            jwks: JwksSource::File(jwks.to_path_buf()),
+            roles_claim: "groups".to_string(),
+            refresh_interval,
+        })
+        .await
+        .unwrap()
+    }
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/oidc").join(name)
+    }
+
+    fn claims(overrides: serde_json::Value) -> serde_json::Value {
+        let now = jsonwebtoken::get_current_timestamp();
+        let mut claims = serde_json::json!({
+            "sub": "alice",
+            "iss": ISSUER,
+            "aud": AUDIENCE,
+            "iat": now,
+            "exp": now + 300,
+            "groups": ["admin", "dev"],
+        });
+        claims.as_object_mut().unwrap().extend(overrides.as_object().unwrap().clone());
+        claims
+    }
+
+    fn sign(kid: &str, key: &str, claims: &serde_json::Value) -> String {
+        let mut header = Header::new(Algorithm::ES256);
+        header.kid = Some(kid.to_string());
+        let pem = std::fs::read(fixture(key)).unwrap();
+        jsonwebtoken::encode(&header, claims, &EncodingKey::from_ec_pem(&pem).unwrap()).unwrap()
+    }
+
+    fn bearer(token: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, format!("Bearer {}", token).parse().unwrap());
+        headers
+    }
+
+    #[tokio::test]
+    async fn matches_api_key_hashes() {
+        let mut keys = vec![api_key("ci", "s3cret", &["pull"])];
+        let mut upper = api_key("ops", "0ther", &["admin"]);
+        upper.sha256 = upper.sha256.to_uppercase();
+        keys.push(upper);
+        let auth = authenticator(keys, None);
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-api-key", "s3cret".parse().unwrap());
+        let identity = auth.identify(&headers).await.unwrap();
+        assert_eq!(identity.subject, "ci");
+        assert_eq!(identity.method, AuthMethod::ApiKey);
+        assert!(identity.has_role("pull") && !identity.has_role("admin"));
+
+        assert_eq!(auth.identify(&bearer("0ther")).await.unwrap().subject, "ops");
+
+        headers.insert("x-api-key", "s3cret ".parse().unwrap());
+        assert_eq!(auth.identify(&headers).await.unwrap_err(), "Invalid API key");
+        assert_eq!(auth.identify(&bearer("wrong")).await.unwrap_err(), "Invalid bearer token");
+        assert_eq!(auth.identify(&HeaderMap::new()).await.unwrap_err(), "Missing credentials");
+    }
+
+    #[tokio::test]
+    async fn validates_tokens() {
+        let oidc = oidc(&fixture("jwks.json"), Duration::from_secs(3600)).await;
+        let identity = oidc.validate(&sign("test-1", "signing-key.pem", &claims(serde_json::json!({})))).await.unwrap();
+        assert_eq!(identity.subject, "alice");
+        assert_eq!(identity.method, AuthMethod::Jwt);
+        assert_eq!(identity.roles, ["admin", "dev"]);
+
+        let identity = oidc
+            .validate(&sign("test-1", "signing-key.pem", &claims(serde_json::json!({"groups": "ops"}))))
+            .await
+            .unwrap();
+        assert_eq!(identity.roles, ["ops"]);
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_tokens() {
+        let oidc = oidc(&fixture("jwks.json"), Duration::from_secs(3600)).await;
+        let past = jsonwebtoken::get_current_timestamp() - 3600;
+        let rejected = [
+            ("wrong issuer", serde_json::json!({"iss": "https://evil.example"})),
+            ("wrong audience", serde_json::json!({"aud": "other"})),
+            ("expired", serde_json::json!({"iat": past - 300, "exp": past})),
+        ];
+        for (case, overrides) in rejected {
+            let token = sign("test-1", "signing-key.pem", &claims(overrides));
+            assert_eq!(oidc.validate(&token).await.unwrap_err(), "Invalid token", "{}", case);
+        }
+
+        // Signed by a key that is not in the JWKS under that key id
+        let token = sign("test-1", "rotated-key.pem", &claims(serde_json::json!({})));
+        assert_eq!(oidc.validate(&token).await.unwrap_err(), "Invalid token");
+
+        let token = sign("test-1", "signing-key.pem", &claims(serde_json::json!({"sub": null})));
+        assert_eq!(oidc.validate(&token).await.unwrap_err(), "Token without subject");
+    }
+
+    #[tokio::test]
+    async fn rejects_symmetric_and_unsigned_tokens() {
+        let oidc = oidc(&fixture("jwks.json"), Duration::from_secs(3600)).await;
+        let mut header = Header::new(Algorithm::HS256);
+        header.kid = Some("test-1".to_string());
+        // HMAC keyed with the public key material, the classic algorithm confusion
+        let secret = std::fs::read(fixture("jwks.json")).unwrap();
+        let token = jsonwebtoken::encode(&header, &claims(serde_json::json!({})), &EncodingKey::from_secret(&secret))
+            .unwrap();
+        assert_eq!(oidc.validate(&token).await.unwrap_err(), "Unsupported token algorithm");
+
+        let b64 = |v: serde_json::Value| base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(v.to_string());
+        let token = format!(
+            "{}.{}.",
+            b64(serde_json::json!({"alg": "none", "typ": "JWT", "kid": "test-1"})),
+            b64(claims(serde_json::json!({})))
+        );
+        assert!(oidc.validate(&token).await.is_err());
+        let auth = authenticator(Vec::new(), Some(oidc));
+        assert!(auth.identify(&bearer(&token)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn refreshes_jwks_at_most_once_per_interval() {
+        let dir = tempfile::tempdir().unwrap();
+        let jwks = dir.path().join("jwks.json");
+        std::fs::copy(fixture("jwks.json"), &jwks).unwrap();
+        let mut oidc = oidc(&jwks, Duration::from_secs(3600)).await;
+        let token = sign("test-2", "rotated-key.pem", &claims(serde_json::json!({})));
+        assert_eq!(oidc.validate(&token).await.unwrap_err(), "Unknown signing key");
+
+        // The issuer rotates its keys: not picked up until the interval has passed
+        std::fs::copy(fixture("jwks-rotated.json"), &jwks).unwrap();
+        assert_eq!(oidc.validate(&token).await.unwrap_err(), "Unknown signing key");
+
+        oidc.config.refresh_interval = Duration::ZERO;
+        assert_eq!(oidc.validate(&token).await.unwrap().subject, "alice");
+    }
+
+    #[tokio::test]
+    async fn admin_routes_require_the_admin_role() {
+        let auth = Arc::new(authenticator(
+            vec![api_key("root", "adminkey", &["admin"]), api_key("ci", "userkey", &["pull"])],
+            None,
+        ));
+        let app = Router::new()
+            .route("/api/admin/audit", get(|| async { "ok" }))
+            .route_layer(middleware::from_fn_with_state(auth.clone(), require_admin))
+            .layer(middleware::from_fn_with_state(auth, authenticate));
+        let status = |key: &'static str| {
+            let request = axum::http::Request::get("/api/admin/audit")
+                .header("x-api-key", key)
+                .body(Body::empty())
+                .unwrap();
+            let app = app.clone();
+            async move { app.oneshot(request).await.unwrap().status() }
+        };
+        assert_eq!(status("adminkey").await, StatusCode::OK);
+        assert_eq!(status("userkey").await, StatusCode::FORBIDDEN);
+        assert_eq!(status("nokey").await, StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,1131 @@
+use serde::{de, Deserialize, Deserializer, Serialize};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    io::Write,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
+};
+use tracing::{error, info, warn};
+use utoipa::ToSchema;
+
+use crate::ratelimit;
+
+// How often the config file is checked for changes
+const WATCH_INTERVAL: Duration = Duration::from_secs(10);
+
+// Settings outside the file, read once at startup by their module's
+// `from_env`. A file section with one of these names is rejected with a
+// pointer to the variables.
+const ENV_ONLY: &[(&str, &str)] = &[
+    ("egress", "EGRESS_ALLOW, EGRESS_DENY, EGRESS_STRICT, EGRESS_ALLOW_PRIVATE"),
+    ("credentialStore", "CREDENTIAL_STORE_PATH, CREDENTIAL_STORE_KEY, CREDENTIAL_STORE_KEY_FILE"),
+    ("dockerAuth", "REGISTRY_AUTH_FILE, DOCKER_CONFIG"),
+    ("audit", "AUDIT_LOG, AUDIT_LOG_MAX_BYTES, AUDIT_LOG_MAX_FILES, AUDIT_BUFFER_SIZE"),
+    ("policy", "PULL_POLICY_FILE"),
+    ("cosign", "COSIGN_PUBLIC_KEYS, COSIGN_ENFORCE"),
+    ("tracing", "OTEL_EXPORTER_OTLP_ENDPOINT, OTEL_SERVICE_NAME, RUST_LOG, LOG_FORMAT"),
+];
+
+// Read by skopeo when CONTAINERS_REGISTRIES_CONF is not set and there is no
+// user file
+const SYSTEM_REGISTRIES_CONF: &str = "/etc/containers/registries.conf";
+
+// Settings read from CONFIG_FILE (TOML or YAML), then overridden by the
+// environment variables the backend has always used (PORT, SKOPEO_PATH, ...)
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields, rename_all = "camelCase")]
+pub struct Config {
+    pub listen: Listen,
+    pub tools: Tools,
+    pub timeouts: Timeouts,
+    pub registries: Vec<Registry>,
+    pub limits: Limits,
+    pub cache: Cache,
+    pub temp: Temp,
+    pub auth: Auth,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields, rename_all = "camelCase")]
+pub struct Listen {
+    pub address: IpAddr,
+    pub port: u16,
+}
+
+impl Default for Listen {
+    fn default() -> Self {
+        Self {
+            address: IpAddr::from([0, 0, 0, 0]),
+            port: 8080,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields, rename_all = "camelCase")]
+pub struct Tools {
+    pub skopeo: String,
+    pub helm: String,
+    // OpenPGP keyring used to verify chart provenance
+    pub helm_keyring: Option<PathBuf>,
+    pub require_chart_verification: bool,
+}
+
+impl Default for Tools {
+    fn default() -> Self {
+        Self {
+            skopeo: "skopeo".to_string(),
+            helm: "helm".to_string(),
+            helm_keyring: None,
+            require_chart_verification: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields, rename_all = "camelCase")]
+pub struct Timeouts {
+    // Registry API and chart repository requests
+    #[serde(deserialize_with = "duration")]
+    pub upstream: Duration,
+    // Chart repository index, registry catalog and tag listings, `upstream` when unset
+    #[serde(deserialize_with = "optional_duration")]
+    pub index: Option<Duration>,
+    #[serde(deserialize_with = "optional_duration")]
+    pub catalog: Option<Duration>,
+    #[serde(deserialize_with = "optional_duration")]
+    pub tags: Option<Duration>,
+    #[serde(deserialize_with = "duration")]
+    pub registry_login: Duration,
+    #[serde(deserialize_with = "duration")]
+    pub image_pull: Duration,
+    #[serde(deserialize_with = "duration")]
+    pub chart_pull: Duration,
+    #[serde(deserialize_with = "duration")]
+    pub signature_verification: Duration,
+    #[serde(deserialize_with = "duration")]
+    pub tool_check: Duration,
+    #[serde(deserialize_with = "duration")]
+    pub shutdown_grace: Duration,
+    // Longest `timeout` a request may ask for, zero disables overrides
+    #[serde(deserialize_with = "duration")]
+    pub max_override: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            upstream: Duration::from_secs(30),
+            index: None,
+            catalog: None,
+            tags: None,
+            registry_login: Duration::from_secs(60),
+            image_pull: Duration::from_secs(300),
+            chart_pull: Duration::from_secs(300),
+            signature_verification: Duration::from_secs(60),
+            tool_check: Duration::from_secs(5),
+            shutdown_grace: Duration::from_secs(25),
+            max_override: Duration::from_secs(3600),
+        }
+    }
+}
+
+impl Timeouts {
+    pub fn index(&self) -> Duration {
+        self.index.unwrap_or(self.upstream)
+    }
+
+    pub fn catalog(&self) -> Duration {
+        self.catalog.unwrap_or(self.upstream)
+    }
+
+    pub fn tags(&self) -> Duration {
+        self.tags.unwrap_or(self.upstream)
+    }
+
+    // Timeout of an operation, or the one the request asked for, which can
+    // be shorter or longer but not above `maxOverride`
+    pub fn with_override(&self, default: Duration, requested: Option<&str>) -> Result<Duration, String> {
+        let Some(requested) = requested.map(str::trim).filter(|t| !t.is_empty()) else {
+            return Ok(default);
+        };
+        if self.max_override.is_zero() {
+            return Err("Timeout overrides are disabled".to_string());
+        }
+        let requested = humantime::parse_duration(requested)
+            .map_err(|e| format!("Invalid timeout {:?}: {}", requested, e))?;
+        if requested.is_zero() {
+            return Err("Timeout must be greater than zero".to_string());
+        }
+        if requested > self.max_override {
+            return Err(format!(
+                "Timeout exceeds the maximum of {}",
+                humantime::format_duration(self.max_override)
+            ));
+        }
+        Ok(requested)
+    }
+}
+
+// An upstream registry, probed for /api/admin/readiness, with optional pull mirrors
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct Registry {
+    pub host: String,
+    #[serde(default)]
+    pub mirrors: Vec<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields, rename_all = "camelCase")]
+pub struct Limits {
+    pub rate_limits: RateLimits,
+    // CIDRs or addresses whose X-Forwarded-For is trusted
+    pub trusted_proxies: Vec<String>,
+    // Readiness fails below these
+    #[serde(deserialize_with = "size")]
+    pub min_free_space: u64,
+    pub min_free_inodes: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            rate_limits: RateLimits::default(),
+            trusted_proxies: Vec::new(),
+            min_free_space: 512 << 20,
+            min_free_inodes: 1000,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields, rename_all = "camelCase")]
+pub struct RateLimits {
+    pub pulls: Option<RateLimit>,
+    pub listings: Option<RateLimit>,
+    pub index: Option<RateLimit>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct RateLimit {
+    // `<requests>/<period>`, e.g. `30/min`
+    pub quota: String,
+    #[serde(default)]
+    pub burst: Option<u32>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields, rename_all = "camelCase")]
+pub struct Cache {
+    // Tool and registry checks behind /ready
+    #[serde(deserialize_with = "duration")]
+    pub readiness: Duration,
+    // Minimum delay between two JWKS refreshes triggered by unknown key ids
+    #[serde(deserialize_with = "duration")]
+    pub jwks_refresh: Duration,
+}
+
+impl Default for Cache {
+    fn default() -> Self {
+        Self {
+            readiness: Duration::from_secs(30),
+            jwks_refresh: Duration::from_secs(60),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields, rename_all = "camelCase")]
+pub struct Temp {
+    #[serde(deserialize_with = "duration")]
+    pub max_age: Duration,
+    #[serde(deserialize_with = "duration")]
+    pub sweep_interval: Duration,
+}
+
+impl Default for Temp {
+    fn default() -> Self {
+        Self {
+            max_age: Duration::from_secs(60 * 60),
+            sweep_interval: Duration::from_secs(10 * 60),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields, rename_all = "camelCase")]
+pub struct Auth {
+    // JSON list of {"id", "sha256", "roles"}
+    pub api_keys_file: Option<PathBuf>,
+    // Role required by the /api/admin routes
+    pub admin_role: String,
+    pub oidc: Option<Oidc>,
+}
+
+impl Default for Auth {
+    fn default() -> Self {
+        Self {
+            api_keys_file: None,
+            admin_role: "admin".to_string(),
+            oidc: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields, rename_all = "camelCase")]
+pub struct Oidc {
+    pub issuer: String,
+    pub audience: Option<String>,
+    pub jwks_url: Option<String>,
+    pub jwks_file: Option<PathBuf>,
+    pub roles_claim: Option<String>,
+}
+
+fn duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    humantime::parse_duration(value.trim())
+        .map_err(|e| de::Error::custom(format!("invalid duration {:?}: {}", value, e)))
+}
+
+fn optional_duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+    duration(deserializer).map(Some)
+}
+
+// A byte count, or a string such as `512MiB`
+fn size<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Size {
+        Bytes(u64),
+        Text(String),
+    }
+    match Size::deserialize(deserializer)? {
+        Size::Bytes(n) => Ok(n),
+        Size::Text(s) => crate::policy::parse_size(&s)
+            .ok_or_else(|| de::Error::custom(format!("invalid size {:?}", s))),
+    }
+}
+
+// Every problem found while loading, reported together
+#[derive(Debug)]
+pub struct ConfigError {
+    source: String,
+    problems: Vec<String>,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid configuration ({}):", self.source)?;
+        for problem in &self.problems {
+            write!(f, "\n  - {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    // CONFIG_FILE when set, then environment overrides, then validation
+    pub fn load(path: Option<&Path>) -> Result<Self, ConfigError> {
+        let source = path.map_or_else(|| "environment".to_string(), |p| p.display().to_string());
+        let fail = |problems| ConfigError {
+            source: source.clone(),
+            problems,
+        };
+        let mut config = match path {
+            Some(path) => Self::read(path).map_err(fail)?,
+            None => Self::default(),
+        };
+        let mut problems = Vec::new();
+        config.apply_env(&mut problems);
+        config.validate(&mut problems);
+        if !problems.is_empty() {
+            return Err(fail(problems));
+        }
+        Ok(config)
+    }
+
+    fn read(path: &Path) -> Result<Self, Vec<String>> {
+        let text = std::fs::read_to_string(path).map_err(|e| vec![format!("cannot read file: {}", e)])?;
+        let (sections, config) = match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => (
+                toml::from_str::<toml::Table>(&text).map(|t| t.keys().cloned().collect()).ok(),
+                toml::from_str(&text).map_err(|e| e.to_string().trim_end().to_string()),
+            ),
+            Some("yaml") | Some("yml") => (
+                serde_yaml::from_str::<BTreeMap<String, serde_yaml::Value>>(&text)
+                    .map(|m| m.into_keys().collect())
+                    .ok(),
+                serde_yaml::from_str(&text).map_err(|e| e.to_string()),
+            ),
+            _ => return Err(vec!["unknown format, use a .toml, .yaml or .yml file".to_string()]),
+        };
+        let sections: Vec<String> = sections.unwrap_or_default();
+        let env_only: Vec<String> = ENV_ONLY
+            .iter()
+            .filter(|(name, _)| sections.iter().any(|s| s == name))
+            .map(|(name, vars)| format!("{}: not read from the file, set {} instead", name, vars))
+            .collect();
+        if !env_only.is_empty() {
+            return Err(env_only);
+        }
+        config.map_err(|e| vec![e])
+    }
+
+    fn apply_env(&mut self, problems: &mut Vec<String>) {
+        override_parsed(&mut self.listen.address, "LISTEN_ADDRESS", problems);
+        override_parsed(&mut self.listen.port, "PORT", problems);
+
+        override_parsed(&mut self.tools.skopeo, "SKOPEO_PATH", problems);
+        override_parsed(&mut self.tools.helm, "HELM_PATH", problems);
+        if let Some(keyring) = env("HELM_KEYRING") {
+            self.tools.helm_keyring = Some(PathBuf::from(keyring));
+        }
+        if let Some(v) = env("HELM_REQUIRE_VERIFY") {
+            self.tools.require_chart_verification = v == "true" || v == "1";
+        }
+
+        let timeouts = &mut self.timeouts;
+        for (field, var) in [
+            (&mut timeouts.upstream, "TIMEOUT_UPSTREAM"),
+            (&mut timeouts.registry_login, "TIMEOUT_REGISTRY_LOGIN"),
+            (&mut timeouts.image_pull, "TIMEOUT_IMAGE_PULL"),
+            (&mut timeouts.chart_pull, "TIMEOUT_CHART_PULL"),
+            (&mut timeouts.signature_verification, "TIMEOUT_SIGNATURE_VERIFICATION"),
+            (&mut timeouts.tool_check, "TIMEOUT_TOOL_CHECK"),
+            (&mut timeouts.shutdown_grace, "SHUTDOWN_GRACE_PERIOD"),
+            (&mut timeouts.max_override, "TIMEOUT_MAX_OVERRIDE"),
+            (&mut self.cache.readiness, "READY_CACHE_TTL"),
+            (&mut self.temp.max_age, "TEMP_MAX_AGE"),
+            (&mut self.temp.sweep_interval, "TEMP_SWEEP_INTERVAL"),
+        ] {
+            override_duration(field, var, problems);
+        }
+        for (field, var) in [
+            (&mut timeouts.index, "TIMEOUT_INDEX"),
+            (&mut timeouts.catalog, "TIMEOUT_CATALOG"),
+            (&mut timeouts.tags, "TIMEOUT_TAGS"),
+        ] {
+            let mut value = field.unwrap_or(timeouts.upstream);
+            override_duration(&mut value, var, problems);
+            if env(var).is_some() {
+                *field = Some(value);
+            }
+        }
+
+        // Hosts to probe; mirrors configured in the file are kept
+        if let Some(hosts) = env("READY_REGISTRIES") {
+            self.registries = list(&hosts)
+                .map(|host| Registry {
+                    mirrors: self
+                        .registries
+                        .iter()
+                        .find(|r| r.host == host)
+                        .map(|r| r.mirrors.clone())
+                        .unwrap_or_default(),
+                    host,
+                })
+                .collect();
+        }
+
+        let rate_limits = &mut self.limits.rate_limits;
+        for (limit, var) in [
+            (&mut rate_limits.pulls, "RATE_LIMIT_PULLS"),
+            (&mut rate_limits.listings, "RATE_LIMIT_LISTINGS"),
+            (&mut rate_limits.index, "RATE_LIMIT_INDEX"),
+        ] {
+            if let Some(quota) = env(var) {
+                *limit = Some(RateLimit { quota, burst: None });
+            }
+            let burst_var = format!("{}_BURST", var);
+            if let Some(burst) = env(&burst_var) {
+                match (limit.as_mut(), burst.parse()) {
+                    (Some(limit), Ok(burst)) => limit.burst = Some(burst),
+                    (None, _) => problems.push(format!("{} is set without a quota", burst_var)),
+                    (_, Err(_)) => problems.push(format!("{}: invalid number {:?}", burst_var, burst)),
+                }
+            }
+        }
+        if let Some(proxies) = env("TRUSTED_PROXIES") {
+            self.limits.trusted_proxies = list(&proxies).collect();
+        }
+        if let Some(v) = env("READY_MIN_FREE_SPACE") {
+            match crate::policy::parse_size(&v) {
+                Some(size) => self.limits.min_free_space = size,
+                None => problems.push(format!("READY_MIN_FREE_SPACE: invalid size {:?}", v)),
+            }
+        }
+        override_parsed(&mut self.limits.min_free_inodes, "READY_MIN_FREE_INODES", problems);
+
+        if let Some(file) = env("API_KEYS_FILE") {
+            self.auth.api_keys_file = Some(PathBuf::from(file));
+        }
+        override_parsed(&mut self.auth.admin_role, "AUTH_ADMIN_ROLE", problems);
+        if let Some(issuer) = env("OIDC_ISSUER") {
+            self.auth.oidc.get_or_insert_with(Oidc::default).issuer = issuer;
+        }
+        if let Some(oidc) = self.auth.oidc.as_mut() {
+            if let Some(audience) = env("OIDC_AUDIENCE") {
+                oidc.audience = Some(audience);
+            }
+            if let Some(url) = env("OIDC_JWKS_URL") {
+                oidc.jwks_url = Some(url);
+            }
+            if let Some(file) = env("OIDC_JWKS_FILE") {
+                oidc.jwks_file = Some(PathBuf::from(file));
+            }
+            if let Some(claim) = env("OIDC_ROLES_CLAIM") {
+                oidc.roles_claim = Some(claim);
+            }
+        }
+    }
+
+    fn validate(&self, problems: &mut Vec<String>) {
+        if self.listen.port == 0 {
+            problems.push("listen.port: must not be 0".to_string());
+        }
+        if self.tools.skopeo.trim().is_empty() {
+            problems.push("tools.skopeo: must not be empty".to_string());
+        }
+        if self.tools.helm.trim().is_empty() {
+            problems.push("tools.helm: must not be empty".to_string());
+        }
+        match &self.tools.helm_keyring {
+            Some(keyring) if !keyring.is_file() => problems.push(format!(
+                "tools.helmKeyring: {} is not a file",
+                keyring.display()
+            )),
+            None if self.tools.require_chart_verification => {
+                problems.push("tools.requireChartVerification: needs tools.helmKeyring".to_string())
+            }
+            _ => {}
+        }
+
+        let timeouts = &self.timeouts;
+        for (name, value) in [
+            ("timeouts.upstream", timeouts.upstream),
+            ("timeouts.index", timeouts.index()),
+            ("timeouts.catalog", timeouts.catalog()),
+            ("timeouts.tags", timeouts.tags()),
+            ("timeouts.registryLogin", timeouts.registry_login),
+            ("timeouts.imagePull", timeouts.image_pull),
+            ("timeouts.chartPull", timeouts.chart_pull),
+            ("timeouts.signatureVerification", timeouts.signature_verification),
+            ("timeouts.toolCheck", timeouts.tool_check),
+            ("temp.sweepInterval", self.temp.sweep_interval),
+        ] {
+            if value.is_zero() {
+                problems.push(format!("{}: must be greater than zero", name));
+            }
+        }
+
+        for (i, registry) in self.registries.iter().enumerate() {
+            if !valid_host(&registry.host) {
+                problems.push(format!("registries[{}].host: invalid registry {:?}", i, registry.host));
+            }
+            for mirror in &registry.mirrors {
+                if !valid_host(mirror) {
+                    problems.push(format!(
+                        "registries[{}].mirrors: invalid mirror {:?}",
+                        i, mirror
+                    ));
+                }
+            }
+        }
+
+        let rate_limits = &self.limits.rate_limits;
+        for (name, limit) in [
+            ("pulls", &rate_limits.pulls),
+            ("listings", &rate_limits.listings),
+            ("index", &rate_limits.index),
+        ] {
+            let Some(limit) = limit else { continue };
+            if ratelimit::Quota::parse(&limit.quota).is_none() {
+                problems.push(format!(
+                    "limits.rateLimits.{}.quota: invalid quota {:?} (expected e.g. 30/min)",
+                    name, limit.quota
+                ));
+            }
+            if limit.burst == Some(0) {
+                problems.push(format!("limits.rateLimits.{}.burst: must be greater than zero", name));
+            }
+        }
+        for proxy in &self.limits.trusted_proxies {
+            if ratelimit::parse_trusted_proxy(proxy).is_none() {
+                problems.push(format!("limits.trustedProxies: invalid address or CIDR {:?}", proxy));
+            }
+        }
+
+        if self.auth.admin_role.trim().is_empty() {
+            problems.push("auth.adminRole: must not be empty".to_string());
+        }
+        if let Some(oidc) = &self.auth.oidc {
+            if oidc.issuer.trim().is_empty() {
+                problems.push("auth.oidc.issuer: must not be empty".to_string());
+            }
+            if oidc.jwks_url.is_none() && oidc.jwks_file.is_none() {
+                problems.push("auth.oidc: needs jwksUrl or jwksFile".to_string());
+            }
+        }
+    }
+
+    // Sections that can change while running; the rest is kept until a restart
+    fn reloadable(&self, mut new: Config) -> (Config, Vec<&'static str>) {
+        let mut ignored = Vec::new();
+        if new.listen != self.listen {
+            ignored.push("listen");
+            new.listen = self.listen.clone();
+        }
+        if new.tools != self.tools {
+            ignored.push("tools");
+            new.tools = self.tools.clone();
+        }
+        if new.limits.rate_limits != self.limits.rate_limits
+            || new.limits.trusted_proxies != self.limits.trusted_proxies
+        {
+            ignored.push("limits.rateLimits/trustedProxies");
+            new.limits.rate_limits = self.limits.rate_limits.clone();
+            new.limits.trusted_proxies = self.limits.trusted_proxies.clone();
+        }
+        if new.cache.jwks_refresh != self.cache.jwks_refresh {
+            ignored.push("cache.jwksRefresh");
+            new.cache.jwks_refresh = self.cache.jwks_refresh;
+        }
+        if new.temp.sweep_interval != self.temp.sweep_interval {
+            ignored.push("temp.sweepInterval");
+            new.temp.sweep_interval = self.temp.sweep_interval;
+        }
+        if new.auth != self.auth {
+            ignored.push("auth");
+            new.auth = self.auth.clone();
+        }
+        (new, ignored)
+    }
+
+    // Configured registries and their mirrors
+    fn upstream_hosts(&self) -> impl Iterator<Item = &str> {
+        self.registries
+            .iter()
+            .flat_map(|r| std::iter::once(&r.host).chain(&r.mirrors))
+            .map(String::as_str)
+    }
+
+    // containers-registries.conf(5) declaring the mirrors, for skopeo. It
+    // takes the place of the system file, whose settings are carried over:
+    // only its entries for the mirrored registries are replaced.
+    fn registries_conf(&self, system: impl FnOnce() -> Option<toml::Table>) -> Option<String> {
+        let table = |entries: Vec<(&str, toml::Value)>| {
+            toml::Value::Table(entries.into_iter().map(|(k, v)| (k.to_string(), v)).collect())
+        };
+        let mirrored: Vec<&Registry> = self.registries.iter().filter(|r| !r.mirrors.is_empty()).collect();
+        if mirrored.is_empty() {
+            return None;
+        }
+        let mut conf = system().unwrap_or_default();
+        let mut entries = match conf.remove("registry") {
+            Some(toml::Value::Array(entries)) => entries,
+            _ => Vec::new(),
+        };
+        // An entry is keyed by its prefix, which defaults to its location
+        entries.retain(|entry| {
+            let prefix = entry.get("prefix").or_else(|| entry.get("location")).and_then(|v| v.as_str());
+            !mirrored.iter().any(|r| prefix == Some(r.host.as_str()))
+        });
+        for registry in mirrored {
+            let mirrors = registry
+                .mirrors
+                .iter()
+                .map(|m| table(vec![("location", m.clone().into())]))
+                .collect::<Vec<_>>();
+            entries.push(table(vec![
+                ("location", registry.host.clone().into()),
+                ("mirror", mirrors.into()),
+            ]));
+        }
+        conf.insert("registry".to_string(), entries.into());
+        toml::to_string(&conf).ok()
+    }
+}
+
+fn env(var: &str) -> Option<String> {
+    std::env::var(var)
+        .ok()
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+}
+
+fn list(value: &str) -> impl Iterator<Item = String> + '_ {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .map(str::to_string)
+}
+
+fn override_parsed<T: std::str::FromStr>(field: &mut T, var: &str, problems: &mut Vec<String>) {
+    if let Some(value) = env(var) {
+        match value.parse() {
+            Ok(parsed) => *field = parsed,
+            Err(_) => problems.push(format!("{}: invalid value {:?}", var, value)),
+        }
+    }
+}
+
+fn override_duration(field: &mut Duration, var: &str, problems: &mut Vec<String>) {
+    if let Some(value) = env(var) {
+        match humantime::parse_duration(&value) {
+            Ok(parsed) => *field = parsed,
+            Err(e) => problems.push(format!("{}: invalid duration {:?}: {}", var, value, e)),
+        }
+    }
+}
+
+// `host[:port]`, as accepted in image references
+fn valid_host(host: &str) -> bool {
+    !host.is_empty()
+        && !host.contains('/')
+        && host
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | ':' | '[' | ']'))
+}
+
+// Load and reload state, reported by /api/admin/readiness
+#[derive(Clone, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+#[schema(as = ConfigStatus)]
+pub struct Status {
+    pub file: Option<String>,
+    pub loaded_at: String,
+    pub reloads: u64,
+    pub last_reload: Option<String>,
+    pub last_reload_error: Option<String>,
+    // Reloaded changes that only apply after a restart
+    pub pending_restart: Vec<&'static str>,
+    pub features: BTreeMap<&'static str, bool>,
+}
+
+// The configuration in effect, swapped on reload
+pub struct LiveConfig {
+    path: Option<PathBuf>,
+    current: RwLock<Arc<Config>>,
+    // Generated registries.conf for the current mirrors, removed when replaced
+    registries_conf: RwLock<Option<Arc<tempfile::TempPath>>>,
+    status: Mutex<Status>,
+}
+
+impl LiveConfig {
+    pub fn load() -> anyhow::Result<Self> {
+        let path = env("CONFIG_FILE").map(PathBuf::from);
+        let config = Config::load(path.as_deref())?;
+        match &path {
+            Some(path) => info!("Configuration loaded from {}", path.display()),
+            None => info!("No CONFIG_FILE, configuration from environment variables"),
+        }
+        Self::from_config(path, config)
+    }
+
+    pub fn from_config(path: Option<PathBuf>, config: Config) -> anyhow::Result<Self> {
+        let registries_conf = write_registries_conf(&config)?;
+        crate::metrics::set_upstream_hosts(config.upstream_hosts());
+        Ok(Self {
+            status: Mutex::new(Status {
+                file: path.as_ref().map(|p| p.display().to_string()),
+                loaded_at: now(),
+                reloads: 0,
+                last_reload: None,
+                last_reload_error: None,
+                pending_restart: Vec::new(),
+                features: BTreeMap::new(),
+            }),
+            path,
+            current: RwLock::new(Arc::new(config)),
+            registries_conf: RwLock::new(registries_conf),
+        })
+    }
+
+    pub fn current(&self) -> Arc<Config> {
+        self.current.read().unwrap().clone()
+    }
+
+    // Keep the returned file alive until skopeo has started
+    pub fn registries_conf(&self) -> Option<Arc<tempfile::TempPath>> {
+        self.registries_conf.read().unwrap().clone()
+    }
+
+    // Features enabled at startup, listed in the status
+    pub fn set_features(&self, features: BTreeMap<&'static str, bool>) {
+        self.status.lock().unwrap().features = features;
+    }
+
+    pub fn status(&self) -> Status {
+        self.status.lock().unwrap().clone()
+    }
+
+    // Re-read the file and environment; an invalid configuration is logged
+    // and the current one kept
+    pub fn reload(&self) {
+        let result = Config::load(self.path.as_deref())
+            .map_err(|e| e.to_string())
+            .and_then(|config| {
+                let conf = write_registries_conf(&config).map_err(|e| e.to_string())?;
+                Ok((config, conf))
+            });
+        let mut status = self.status.lock().unwrap();
+        status.last_reload = Some(now());
+        match result {
+            Ok((config, conf)) => {
+                let (config, ignored) = self.current().reloadable(config);
+                for section in &ignored {
+                    warn!("Configuration change to {} needs a restart", section);
+                }
+                crate::metrics::set_upstream_hosts(config.upstream_hosts());
+                *self.current.write().unwrap() = Arc::new(config);
+                *self.registries_conf.write().unwrap() = conf;
+                status.reloads += 1;
+                status.last_reload_error = None;
+                status.pending_restart = ignored;
+                info!("Configuration reloaded");
+            }
+            Err(e) => {
+                error!("Configuration reload failed, keeping the current one: {}", e);
+                status.last_reload_error = Some(e);
+            }
+        }
+    }
+
+    // Reload on SIGHUP, and when the file's modification time or size changes
+    pub async fn watch(self: Arc<Self>) {
+        let fingerprint = |path: &Path| {
+            std::fs::metadata(path)
+                .ok()
+                .map(|m| (m.modified().ok(), m.len()))
+        };
+        let mut last = self.path.as_deref().and_then(fingerprint);
+        let mut ticker = tokio::time::interval(WATCH_INTERVAL);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        #[cfg(unix)]
+        let mut hangup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+            Ok(signal) => Some(signal),
+            Err(e) => {
+                warn!("Failed to listen for SIGHUP: {}", e);
+                None
+            }
+        };
+        loop {
+            #[cfg(unix)]
+            let hangup_received = async {
+                match hangup.as_mut() {
+                    Some(signal) => signal.recv().await,
+                    None => std::future::pending().await,
+                }
+            };
+            #[cfg(not(unix))]
+            let hangup_received = std::future::pending::<Option<()>>();
+
+            tokio::select! {
+                _ = hangup_received => {
+                    info!("Received SIGHUP, reloading configuration");
+                }
+                _ = ticker.tick() => {
+                    let Some(path) = self.path.as_deref() else { continue };
+                    let current = fingerprint(path);
+                    if current == last {
+                        continue;
+                    }
+                    last = current;
+                    info!("{} changed, reloading configuration", path.display());
+                }
+            }
+            self.reload();
+        }
+    }
+}
+
+fn now() -> String {
+    humantime::format_rfc3339_seconds(SystemTime::now()).to_string()
+}
+
+// The registries.conf skopeo would read without ours
+fn system_registries_conf() -> Option<toml::Table> {
+    let user = env("HOME").map(|home| PathBuf::from(home).join(".config/containers/registries.conf"));
+    let path = env("CONTAINERS_REGISTRIES_CONF")
+        .map(PathBuf::from)
+        .or(user.filter(|p| p.is_file()))
+        .unwrap_or_else(|| PathBuf::from(SYSTEM_REGISTRIES_CONF));
+    let text = match std::fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+        Err(e) => {
+            warn!("Failed to read {}, its settings do not apply to mirrored pulls: {}", path.display(), e);
+            return None;
+        }
+    };
+    match toml::from_str::<toml::Table>(&text) {
+        // Version 1 tables cannot be mixed with the `[[registry]]` entries
+        Ok(table) if table.contains_key("registries") => {
+            warn!("{} uses the v1 format, its settings do not apply to mirrored pulls", path.display());
+            None
+        }
+        Ok(table) => Some(table),
+        Err(e) => {
+            warn!("Invalid {}, its settings do not apply to mirrored pulls: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+fn write_registries_conf(config: &Config) -> anyhow::Result<Option<Arc<tempfile::TempPath>>> {
+    let Some(conf) = config.registries_conf(system_registries_conf) else {
+        return Ok(None);
+    };
+    let mut file = tempfile::Builder::new()
+        .prefix("tessark-registries-")
+        .suffix(".conf")
+        .tempfile_in(crate::janitor::temp_dir())?;
+    file.write_all(conf.as_bytes())?;
+    Ok(Some(Arc::new(file.into_temp_path())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(name: &str, text: &str) -> (tempfile::TempDir, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(name);
+        std::fs::write(&path, text).unwrap();
+        (dir, path)
+    }
+
+    fn read(name: &str, text: &str) -> Result<Config, Vec<String>> {
+        let (_dir, path) = file(name, text);
+        Config::read(&path)
+    }
+
+    #[test]
+    fn reads_toml_and_yaml() {
+        let yaml = read(
+            "tessark.yaml",
+            "listen:\n  port: 9000\ntimeouts:\n  upstream: 10s\n\
+             registries:\n  - host: docker.io\n    mirrors: [mirror.gcr.io]\n",
+        )
+        .unwrap();
+        let toml = read(
+            "tessark.toml",
+            "listen = { port = 9000 }\ntimeouts = { upstream = \"10s\" }\n\n\
+             [[registries]]\nhost = \"docker.io\"\nmirrors = [\"mirror.gcr.io\"]\n",
+        )
+        .unwrap();
+        assert_eq!(yaml, toml);
+        assert_eq!(yaml.listen.port, 9000);
+        assert_eq!(yaml.timeouts.index(), Duration::from_secs(10));
+        assert_eq!(yaml.timeouts.image_pull, Timeouts::default().image_pull);
+        assert!(read("tessark.json", "{}").is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_fields() {
+        let cases = [
+            ("tessark.yaml", "listen:\n  port: 8080\n  host: example\n", "unknown field `host`"),
+            ("tessark.yaml", "timeout:\n  upstream: 10s\n", "unknown field `timeout`"),
+            ("tessark.toml", "[tools]\nkubectl = \"kubectl\"\n", "unknown field `kubectl`"),
+            ("tessark.yaml", "registries:\n  - host: ghcr.io\n    mirror: [a.example]\n", "unknown field `mirror`"),
+            ("tessark.yaml", "egress:\n  strict: true\n", "egress: not read from the file, set EGRESS_ALLOW"),
+            ("tessark.toml", "[cosign]\nenforce = [\"*\"]\n", "cosign: not read from the file, set COSIGN_PUBLIC_KEYS"),
+        ];
+        for (name, text, expected) in cases {
+            let problems = read(name, text).unwrap_err();
+            assert!(problems.iter().any(|p| p.contains(expected)), "{:?}: {:?}", text, problems);
+        }
+    }
+
+    #[test]
+    fn reports_every_problem() {
+        let mut config = Config::default();
+        config.listen.port = 0;
+        config.tools.skopeo = " ".to_string();
+        config.tools.require_chart_verification = true;
+        config.timeouts.upstream = Duration::ZERO;
+        config.timeouts.tags = Some(Duration::from_secs(5));
+        config.registries = vec![Registry {
+            host: "ghcr.io/org".to_string(),
+            mirrors: vec!["mirror example".to_string()],
+        }];
+        config.limits.rate_limits.pulls = Some(RateLimit {
+            quota: "lots".to_string(),
+            burst: Some(0),
+        });
+        config.limits.trusted_proxies = vec!["10.0.0.0/33".to_string()];
+        config.auth.admin_role = String::new();
+        config.auth.oidc = Some(Oidc::default());
+
+        let mut problems = Vec::new();
+        config.validate(&mut problems);
+        let expected = [
+            "listen.port",
+            "tools.skopeo",
+            "tools.requireChartVerification",
+            "timeouts.upstream",
+            "timeouts.index",
+            "timeouts.catalog",
+            "registries[0].host",
+            "registries[0].mirrors",
+            "limits.rateLimits.pulls.quota",
+            "limits.rateLimits.pulls.burst",
+            "limits.trustedProxies",
+            "auth.adminRole",
+            "auth.oidc.issuer",
+            "auth.oidc: needs jwksUrl or jwksFile",
+        ];
+        assert_eq!(problems.len(), expected.len(), "{:#?}", problems);
+        for (problem, expected) in problems.iter().zip(expected) {
+            assert!(problem.starts_with(expected), "{} / {}", problem, expected);
+        }
+
+        let mut problems = Vec::new();
+        Config::default().validate(&mut problems);
+        assert!(problems.is_empty(), "{:?}", problems);
+    }
+
+    // The only test touching the environment, which is shared by the test threads
+    #[test]
+    fn environment_overrides_the_file() {
+        let (_dir, path) = file(
+            "tessark.yaml",
+            "listen:\n  port: 8081\ntimeouts:\n  upstream: 10s\n  tags: 20s\n\
+             registries:\n  - host: docker.io\n    mirrors: [mirror.gcr.io]\n  - host: ghcr.io\n",
+        );
+        let vars = [
+            ("PORT", "9090"),
+            ("TIMEOUT_TAGS", "5s"),
+            ("READY_REGISTRIES", "docker.io, quay.io"),
+            ("RATE_LIMIT_PULLS", "30/min"),
+            ("RATE_LIMIT_PULLS_BURST", "60"),
+        ];
+        for (var, value) in vars {
+            std::env::set_var(var, value);
+        }
+        let config = Config::load(Some(&path));
+
+        std::env::set_var("PORT", "http");
+        std::env::set_var("RATE_LIMIT_PULLS_BURST", "many");
+        std::env::set_var("TIMEOUT_UPSTREAM", "soon");
+        let invalid = Config::load(Some(&path));
+        for (var, _) in vars {
+            std::env::remove_var(var);
+        }
+        std::env::remove_var("TIMEOUT_UPSTREAM");
+
+        let config = config.unwrap();
+        assert_eq!(config.listen.port, 9090);
+        assert_eq!(config.timeouts.upstream, Duration::from_secs(10));
+        assert_eq!(config.timeouts.tags(), Duration::from_secs(5));
+        assert_eq!(config.timeouts.catalog(), Duration::from_secs(10));
+        assert_eq!(
+            config.registries,
+            [
+                Registry {
+                    host: "docker.io".to_string(),
+                    mirrors: vec!["mirror.gcr.io".to_string()],
+                },
+                Registry {
+                    host: "quay.io".to_string(),
+                    mirrors: Vec::new(),
+                },
+            ]
+        );
+        let pulls = config.limits.rate_limits.pulls.unwrap();
+        assert_eq!((pulls.quota.as_str(), pulls.burst), ("30/min", Some(60)));
+
+        let message = invalid.unwrap_err().to_string();
+        for var in ["PORT", "RATE_LIMIT_PULLS_BURST", "TIMEOUT_UPSTREAM"] {
+            assert!(message.contains(&format!("\n  - {}:", var)), "{}", message);
+        }
+    }
+
+    #[test]
+    fn reload_keeps_restart_only_sections() {
+        let current = Config::default();
+        let mut new = Config::default();
+        new.listen.port = 9000;
+        new.tools.helm = "/opt/helm".to_string();
+        new.timeouts.upstream = Duration::from_secs(5);
+        new.registries = vec![Registry {
+            host: "ghcr.io".to_string(),
+            mirrors: Vec::new(),
+        }];
+        new.limits.min_free_space = 1 << 30;
+        new.limits.trusted_proxies = vec!["10.0.0.0/8".to_string()];
+        new.cache.readiness = Duration::from_secs(5);
+        new.temp.max_age = Duration::from_secs(60);
+        new.temp.sweep_interval = Duration::from_secs(60);
+        new.auth.admin_role = "ops".to_string();
+
+        let (applied, ignored) = current.reloadable(new.clone());
+        assert_eq!(
+            ignored,
+            ["listen", "tools", "limits.rateLimits/trustedProxies", "temp.sweepInterval", "auth"]
+        );
+        assert_eq!(applied.listen, current.listen);
+        assert_eq!(applied.tools, current.tools);
+        assert_eq!(applied.limits.trusted_proxies, current.limits.trusted_proxies);
+        assert_eq!(applied.temp.sweep_interval, current.temp.sweep_interval);
+        assert_eq!(applied.auth, current.auth);
+        assert_eq!(applied.timeouts, new.timeouts);
+        assert_eq!(applied.registries, new.registries);
+        assert_eq!(applied.limits.min_free_space, new.limits.min_free_space);
+        assert_eq!(applied.cache.readiness, new.cache.readiness);
+        assert_eq!(applied.temp.max_age, new.temp.max_age);
+
+        let (_, ignored) = current.reloadable(current.clone());
+        assert!(ignored.is_empty());
+    }
+
+    #[test]
+    fn writes_registries_conf() {
+        let mut config = Config {
+            registries: vec![Registry {
+                host: "ghcr.io".to_string(),
+                mirrors: Vec::new(),
+            }],
+            ..Config::default()
+        };
+        assert_eq!(config.registries_conf(|| None), None);
+
+        config.registries.push(Registry {
+            host: "docker.io".to_string(),
+            mirrors: vec!["mirror.gcr.io".to_string(), "registry.local:5000".to_string()],
+        });
+        let conf: toml::Table = toml::from_str(&config.registries_conf(|| None).unwrap()).unwrap();
+        let expected: toml::Table = toml::from_str(
+            "[[registry]]\nlocation = \"docker.io\"\n\
+             [[registry.mirror]]\nlocation = \"mirror.gcr.io\"\n\
+             [[registry.mirror]]\nlocation = \"registry.local:5000\"\n",
+        )
+        .unwrap();
+        assert_eq!(conf, expected);
+
+        // System settings are kept, its entry for the mirrored registry replaced
+        let system: toml::Table = toml::from_str(
+            "unqualified-search-registries = [\"docker.io\"]\nshort-name-mode = \"enforcing\"\n\n\
+             [[registry]]\nlocation = \"docker.io\"\ninsecure = true\n\n\
+             [[registry]]\nprefix = \"quay.io\"\nlocation = \"quay.io\"\nblocked = true\n",
+        )
+        .unwrap();
+        let conf: toml::Table = toml::from_str(&config.registries_conf(|| Some(system)).unwrap()).unwrap();
+        assert_eq!(conf["unqualified-search-registries"].as_array().unwrap().len(), 1);
+        assert_eq!(conf["short-name-mode"].as_str(), Some("enforcing"));
+        let registries = conf["registry"].as_array().unwrap();
+        assert_eq!(registries.len(), 2);
+        assert_eq!(registries[0]["location"].as_str(), Some("quay.io"));
+        assert_eq!(registries[0]["blocked"].as_bool(), Some(true));
+        assert_eq!(registries[1], expected["registry"].as_array().unwrap()[0]);
+    }
+}
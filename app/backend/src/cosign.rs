@@ -0,0 +1,568 @@
+use base64::Engine;
+use ed25519_dalek::pkcs8::DecodePublicKey;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use tracing::{debug, info, warn};
+use utoipa::ToSchema;
+
+use crate::{
+    policy::glob_match,
+    registry::{self, Credentials, Reference, RegistryError},
+};
+
+// Annotation holding the base64 signature of a simple signing payload layer
+const SIGNATURE_ANNOTATION: &str = "dev.cosignproject.cosign/signature";
+// Artifact type of signatures attached as OCI 1.1 referrers
+const SIGNATURE_ARTIFACT: &str = "application/vnd.dev.cosign.artifact.sig.v1+json";
+const OCI_INDEX: &str = "application/vnd.oci.image.index.v1+json";
+// Older cosign releases push signature manifests with the docker media type
+const SIGNATURE_MANIFESTS: &str = "application/vnd.oci.image.manifest.v1+json, \
+    application/vnd.docker.distribution.manifest.v2+json";
+// Signature payloads are a few hundred bytes
+const MAX_PAYLOAD: u64 = 64 * 1024;
+// Layers examined per image, so a hostile registry cannot make us loop
+const MAX_SIGNATURES: usize = 32;
+
+enum Key {
+    P256(p256::ecdsa::VerifyingKey),
+    Ed25519(ed25519_dalek::VerifyingKey),
+}
+
+impl Key {
+    fn from_pem(pem: &str) -> Option<Self> {
+        if let Ok(key) = p256::ecdsa::VerifyingKey::from_public_key_pem(pem) {
+            return Some(Key::P256(key));
+        }
+        ed25519_dalek::VerifyingKey::from_public_key_pem(pem).ok().map(Key::Ed25519)
+    }
+
+    // ECDSA signatures are DER over the SHA-256 of the payload, Ed25519 ones
+    // sign the payload itself
+    fn verify(&self, payload: &[u8], signature: &[u8]) -> bool {
+        match self {
+            Key::P256(key) => {
+                use p256::ecdsa::signature::Verifier;
+                p256::ecdsa::DerSignature::from_bytes(signature)
+                    .is_ok_and(|sig| key.verify(payload, &sig).is_ok())
+            }
+            Key::Ed25519(key) => ed25519_dalek::Signature::from_slice(signature)
+                .is_ok_and(|sig| key.verify_strict(payload, &sig).is_ok()),
+        }
+    }
+}
+
+struct NamedKey {
+    // File name, reported with successful verifications
+    name: String,
+    key: Key,
+}
+
+#[derive(Deserialize)]
+struct SignatureManifest {
+    #[serde(default)]
+    layers: Vec<Layer>,
+}
+
+#[derive(Deserialize)]
+struct Layer {
+    digest: String,
+    #[serde(default)]
+    annotations: std::collections::BTreeMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct ReferrersIndex {
+    #[serde(default)]
+    manifests: Vec<Referrer>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Referrer {
+    digest: String,
+    #[serde(default)]
+    artifact_type: Option<String>,
+}
+
+// Simple signing payload: only the signed image digest matters here
+#[derive(Deserialize)]
+struct Payload {
+    critical: Critical,
+}
+
+#[derive(Deserialize)]
+struct Critical {
+    image: PayloadImage,
+}
+
+#[derive(Deserialize)]
+struct PayloadImage {
+    #[serde(rename = "docker-manifest-digest")]
+    docker_manifest_digest: String,
+}
+
+struct Signature {
+    payload: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+#[serde(rename_all = "camelCase")]
+#[schema(as = SignatureVerification)]
+pub struct Verification {
+    pub reference: String,
+    pub digest: String,
+    pub verified: bool,
+    // Whether pulls of this repository require a valid signature
+    pub enforced: bool,
+    // Signatures found for the digest, valid or not
+    pub signatures: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+pub struct Verifier {
+    keys: Vec<NamedKey>,
+    enforce: Vec<String>,
+}
+
+impl Verifier {
+    // COSIGN_PUBLIC_KEYS: comma-separated PEM files or directories of them.
+    // COSIGN_ENFORCE: comma-separated `registry/repository` patterns whose pulls
+    // must be signed (`*` for all).
+    pub async fn from_env() -> anyhow::Result<Option<Self>> {
+        let paths = list_env("COSIGN_PUBLIC_KEYS");
+        let enforce = list_env("COSIGN_ENFORCE");
+        if paths.is_empty() {
+            if !enforce.is_empty() {
+                anyhow::bail!("COSIGN_ENFORCE is set but COSIGN_PUBLIC_KEYS is not");
+            }
+            return Ok(None);
+        }
+
+        let mut keys = Vec::new();
+        for path in paths {
+            let path = PathBuf::from(path);
+            if tokio::fs::metadata(&path).await.is_ok_and(|m| m.is_dir()) {
+                let mut entries = tokio::fs::read_dir(&path)
+                    .await
+                    .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", path.display(), e))?;
+                while let Some(entry) = entries.next_entry().await? {
+                    let file = entry.path();
+                    if file.extension().is_some_and(|ext| ext == "pub" || ext == "pem") {
+                        keys.push(load_key(&file).await?);
+                    }
+                }
+            } else {
+                keys.push(load_key(&path).await?);
+            }
+        }
+        if keys.is_empty() {
+            anyhow::bail!("COSIGN_PUBLIC_KEYS does not contain any key");
+        }
+        info!(
+            "Loaded {} cosign public keys, enforced for {:?}",
+            keys.len(),
+            enforce
+        );
+        Ok(Some(Self { keys, enforce }))
+    }
+
+    pub fn enforced(&self, reference: &Reference) -> bool {
+        let name = reference.normalized().name();
+        self.enforce.iter().any(|p| glob_match(p, &name))
+    }
+
+    // Look up the signatures of the image (the `sha256-<hex>.sig` tag and OCI
+    // referrers) and check them against the configured keys. The returned digest
+    // is the one that was verified; pull it rather than the tag.
+    pub async fn verify(
+        &self,
+        client: &reqwest::Client,
+        reference: &Reference,
+        creds: Option<&Credentials>,
+    ) -> Result<Verification, RegistryError> {
+        let digest = registry::manifest_digest(client, reference, creds).await?;
+        let signatures = signatures(client, reference, &digest, creds).await?;
+
+        let mut verification = Verification {
+            reference: reference.name(),
+            digest: digest.clone(),
+            verified: false,
+            enforced: self.enforced(reference),
+            signatures: signatures.len(),
+            key: None,
+            reason: None,
+        };
+        if signatures.is_empty() {
+            verification.reason = Some("no signature found".to_string());
+            return Ok(verification);
+        }
+
+        for signature in &signatures {
+            match serde_json::from_slice::<Payload>(&signature.payload) {
+                Ok(payload) if payload.critical.image.docker_manifest_digest == digest => {}
+                Ok(payload) => {
+                    debug!(
+                        "Signature payload is for {}, not {}",
+                        payload.critical.image.docker_manifest_digest, digest
+                    );
+                    continue;
+                }
+                Err(e) => {
+                    debug!("Invalid signature payload: {}", e);
+                    continue;
+                }
+            }
+            if let Some(key) = self
+                .keys
+                .iter()
+                .find(|k| k.key.verify(&signature.payload, &signature.signature))
+            {
+                verification.verified = true;
+                verification.key = Some(key.name.clone());
+                return Ok(verification);
+            }
+        }
+        verification.reason = Some("no signature matches a trusted key".to_string());
+        Ok(verification)
+    }
+}
+
+async fn load_key(path: &Path) -> anyhow::Result<NamedKey> {
+    let pem = tokio::fs::read_to_string(path)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to read cosign key {}: {}", path.display(), e))?;
+    let key = Key::from_pem(&pem).ok_or_else(|| {
+        anyhow::anyhow!(
+            "Invalid cosign key {}: expected an ECDSA P-256 or Ed25519 public key",
+            path.display()
+        )
+    })?;
+    Ok(NamedKey {
+        name: path
+            .file_name()
+            .map_or_else(|| path.display().to_string(), |n| n.to_string_lossy().into_owned()),
+        key,
+    })
+}
+
+fn list_env(var: &str) -> Vec<String> {
+    std::env::var(var)
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+async fn signatures(
+    client: &reqwest::Client,
+    reference: &Reference,
+    digest: &str,
+    creds: Option<&Credentials>,
+) -> Result<Vec<Signature>, RegistryError> {
+    let base_url = reference.base_url();
+    let mut manifests = Vec::new();
+
+    // Tag scheme used by cosign by default
+    let tag = digest.replace(':', "-") + ".sig";
+    if let Some(manifest) = signature_manifest(client, reference, &tag, creds).await? {
+        manifests.push(manifest);
+    }
+
+    // OCI 1.1 referrers; registries without the API answer 404
+    let url = format!(
+        "{}/v2/{}/referrers/{}?artifactType={}",
+        base_url, reference.repository, digest, SIGNATURE_ARTIFACT
+    );
+    let response = registry::get(client, &url, Some(OCI_INDEX), creds).await?;
+    if response.status().is_success() {
+        let text = response.text().await?;
+        let index: ReferrersIndex =
+            serde_json::from_str(&text).map_err(|e| RegistryError::InvalidResponse(e.to_string()))?;
+        for referrer in index
+            .manifests
+            .iter()
+            .filter(|r| r.artifact_type.as_deref() == Some(SIGNATURE_ARTIFACT))
+            .take(MAX_SIGNATURES)
+        {
+            if let Some(manifest) = signature_manifest(client, reference, &referrer.digest, creds).await? {
+                manifests.push(manifest);
+            }
+        }
+    } else {
+        debug!("No referrers for {} ({})", digest, response.status());
+    }
+
+    let mut signatures = Vec::new();
+    let layers = manifests
+        .iter()
+        .flat_map(|m| &m.layers)
+        .filter_map(|l| l.annotations.get(SIGNATURE_ANNOTATION).map(|s| (l, s)))
+        .take(MAX_SIGNATURES);
+    for (layer, signature) in layers {
+        let Ok(signature) = base64::engine::general_purpose::STANDARD.decode(signature.trim()) else {
+            warn!("Invalid signature annotation on layer {}", layer.digest);
+            continue;
+        };
+        let payload = registry::get_blob(client, reference, &layer.digest, MAX_PAYLOAD, creds).await?;
+        signatures.push(Signature { payload, signature });
+    }
+    Ok(signatures)
+}
+
+async fn signature_manifest(
+    client: &reqwest::Client,
+    reference: &Reference,
+    target: &str,
+    creds: Option<&Credentials>,
+) -> Result<Option<SignatureManifest>, RegistryError> {
+    let url = format!("{}/v2/{}/manifests/{}", reference.base_url(), reference.repository, target);
+    debug!("Fetching signature manifest from: {}", url);
+    let response = registry::get(client, &url, Some(SIGNATURE_MANIFESTS), creds).await?;
+    if response.status() == http::StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    if !response.status().is_success() {
+        return Err(RegistryError::from_response(&response));
+    }
+    let text = response.text().await?;
+    serde_json::from_str(&text)
+        .map(Some)
+        .map_err(|e| RegistryError::InvalidResponse(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{http::Uri, response::IntoResponse, Router};
+    use sha2::{Digest, Sha256};
+    use std::collections::HashMap;
+
+    const IMAGE: &[u8] = br#"{"schemaVersion":2,"mediaType":"application/vnd.oci.image.manifest.v1+json"}"#;
+
+    fn sha256(data: &[u8]) -> String {
+        format!("sha256:{}", hex::encode(Sha256::digest(data)))
+    }
+
+    fn fixture(name: &str) -> String {
+        std::fs::read_to_string(Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/cosign").join(name)).unwrap()
+    }
+
+    fn verifier(keys: &[&str]) -> Verifier {
+        Verifier {
+            keys: keys
+                .iter()
+                .map(|name| NamedKey {
+                    name: name.to_string(),
+                    key: Key::from_pem(&fixture(name)).unwrap(),
+                })
+                .collect(),
+            enforce: vec!["*/org/*".to_string()],
+        }
+    }
+
+    fn payload(digest: &str) -> Vec<u8> {
+        serde_json::json!({
+            "critical": {
+                "identity": {"docker-reference": "registry.example/org/app"},
+                "image": {"docker-manifest-digest": digest},
+                "type": "cosign container image signature",
+            },
+            "optional": null,
+        })
+        .to_string()
+        .into_bytes()
+    }
+
+    fn sign(key: &str, payload: &[u8]) -> Vec<u8> {
+        use ed25519_dalek::pkcs8::DecodePrivateKey;
+        use p256::ecdsa::signature::Signer;
+
+        let pem = fixture(key);
+        match key {
+            "p256.key" => {
+                let key = p256::ecdsa::SigningKey::from_pkcs8_pem(&pem).unwrap();
+                let signature: p256::ecdsa::Signature = key.sign(payload);
+                signature.to_der().as_bytes().to_vec()
+            }
+            _ => {
+                let key = ed25519_dalek::SigningKey::from_pkcs8_pem(&pem).unwrap();
+                key.sign(payload).to_bytes().to_vec()
+            }
+        }
+    }
+
+    // A registry holding `org/app:1.0` and its signatures, by tag and/or as referrers
+    #[derive(Default)]
+    struct Registry {
+        files: HashMap<String, Vec<u8>>,
+    }
+
+    impl Registry {
+        fn new() -> Self {
+            let mut registry = Self::default();
+            registry.put("manifests/1.0", IMAGE.to_vec());
+            registry
+        }
+
+        fn put(&mut self, path: &str, data: Vec<u8>) {
+            self.files.insert(format!("/v2/org/app/{}", path), data);
+        }
+
+        // Signature manifest whose layer is the signed payload as served by the registry
+        fn signature(&mut self, signature: &[u8], served: &[u8]) -> Vec<u8> {
+            self.put(&format!("blobs/{}", sha256(served)), served.to_vec());
+            serde_json::json!({
+                "schemaVersion": 2,
+                "layers": [{
+                    "digest": sha256(served),
+                    "annotations": {SIGNATURE_ANNOTATION: base64::engine::general_purpose::STANDARD.encode(signature)},
+                }],
+            })
+            .to_string()
+            .into_bytes()
+        }
+
+        fn tag(&mut self, manifest: Vec<u8>) {
+            self.put(&format!("manifests/{}.sig", sha256(IMAGE).replace(':', "-")), manifest);
+        }
+
+        fn referrer(&mut self, manifest: Vec<u8>) {
+            let digest = sha256(&manifest);
+            self.put(&format!("manifests/{}", digest), manifest);
+            let index = serde_json::json!({
+                "schemaVersion": 2,
+                "manifests": [{"digest": digest, "artifactType": SIGNATURE_ARTIFACT}],
+            });
+            self.put(&format!("referrers/{}", sha256(IMAGE)), index.to_string().into_bytes());
+        }
+
+        async fn verify(self, verifier: &Verifier) -> Verification {
+            let files = std::sync::Arc::new(self.files);
+            let app = Router::new().fallback(move |uri: Uri| {
+                let files = files.clone();
+                async move {
+                    match files.get(uri.path()) {
+                        Some(data) => data.clone().into_response(),
+                        None => http::StatusCode::NOT_FOUND.into_response(),
+                    }
+                }
+            });
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            tokio::spawn(async move { axum::serve(listener, app).await });
+
+            let reference = Reference {
+                registry: format!("http://{}", addr),
+                repository: "org/app".to_string(),
+                tag: Some("1.0".to_string()),
+                digest: None,
+            };
+            verifier.verify(&reqwest::Client::new(), &reference, None).await.unwrap()
+        }
+    }
+
+    #[tokio::test]
+    async fn verifies_p256_signature_by_tag() {
+        let payload = payload(&sha256(IMAGE));
+        let mut registry = Registry::new();
+        let manifest = registry.signature(&sign("p256.key", &payload), &payload);
+        // No referrers API: the `sha256-<hex>.sig` tag is enough
+        registry.tag(manifest);
+
+        let verification = registry.verify(&verifier(&["ed25519.pub", "p256.pub"])).await;
+        assert!(verification.verified, "{:?}", verification);
+        assert!(verification.enforced);
+        assert_eq!(verification.digest, sha256(IMAGE));
+        assert_eq!(verification.signatures, 1);
+        assert_eq!(verification.key.as_deref(), Some("p256.pub"));
+    }
+
+    #[tokio::test]
+    async fn verifies_ed25519_signature_by_referrer() {
+        let payload = payload(&sha256(IMAGE));
+        let mut registry = Registry::new();
+        let manifest = registry.signature(&sign("ed25519.key", &payload), &payload);
+        registry.referrer(manifest);
+
+        let verification = registry.verify(&verifier(&["p256.pub", "ed25519.pub"])).await;
+        assert!(verification.verified, "{:?}", verification);
+        assert_eq!(verification.key.as_deref(), Some("ed25519.pub"));
+    }
+
+    #[tokio::test]
+    async fn rejects_tampered_payload() {
+        let payload = payload(&sha256(IMAGE));
+        let mut tampered = payload.clone();
+        tampered.extend_from_slice(b" ");
+        let mut registry = Registry::new();
+        let manifest = registry.signature(&sign("p256.key", &payload), &tampered);
+        registry.tag(manifest);
+
+        let verification = registry.verify(&verifier(&["p256.pub"])).await;
+        assert!(!verification.verified);
+        assert_eq!(verification.signatures, 1);
+        assert_eq!(verification.reason.as_deref(), Some("no signature matches a trusted key"));
+    }
+
+    #[tokio::test]
+    async fn rejects_payload_for_another_digest() {
+        let payload = payload(&sha256(b"another image"));
+        let mut registry = Registry::new();
+        let manifest = registry.signature(&sign("p256.key", &payload), &payload);
+        registry.tag(manifest);
+
+        let verification = registry.verify(&verifier(&["p256.pub"])).await;
+        assert!(!verification.verified);
+        assert_eq!(verification.signatures, 1);
+        assert_eq!(verification.reason.as_deref(), Some("no signature matches a trusted key"));
+    }
+
+    #[tokio::test]
+    async fn rejects_untrusted_key() {
+        let payload = payload(&sha256(IMAGE));
+        let mut registry = Registry::new();
+        let manifest = registry.signature(&sign("ed25519.key", &payload), &payload);
+        registry.tag(manifest);
+
+        let verification = registry.verify(&verifier(&["p256.pub"])).await;
+        assert!(!verification.verified);
+        assert_eq!(verification.reason.as_deref(), Some("no signature matches a trusted key"));
+    }
+
+    #[tokio::test]
+    async fn reports_missing_signature() {
+        let verification = Registry::new().verify(&verifier(&["p256.pub"])).await;
+        assert!(!verification.verified);
+        assert_eq!(verification.signatures, 0);
+        assert_eq!(verification.reason.as_deref(), Some("no signature found"));
+    }
+
+    #[test]
+    fn enforcement_matches_registry_aliases() {
+        let verifier = Verifier {
+            enforce: vec!["docker.io/acme/*".to_string(), "registry.corp.example/**".to_string()],
+            ..verifier(&["p256.pub"])
+        };
+        for reference in [
+            "docker.io/acme/app:1.0.0",
+            "Docker.io/acme/app:1.0.0",
+            "index.docker.io/acme/app:1.0.0",
+            "registry-1.docker.io/acme/app:1.0.0",
+            "REGISTRY-1.DOCKER.IO/acme/app@sha256:abc",
+            "registry.hub.docker.com/acme/app",
+            "acme/app:1.0.0",
+            "Registry.Corp.Example/team/app:1.0.0",
+        ] {
+            assert!(verifier.enforced(&Reference::parse(reference)), "{}", reference);
+        }
+        for reference in ["docker.io/other/app:1.0.0", "ghcr.io/acme/app:1.0.0", "nginx:1.25"] {
+            assert!(!verifier.enforced(&Reference::parse(reference)), "{}", reference);
+        }
+    }
+}
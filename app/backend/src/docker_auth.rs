@@ -0,0 +1,346 @@
+use base64::Engine;
+use serde::Deserialize;
+use std::{collections::HashMap, path::PathBuf, process::Stdio, time::Duration};
+use tokio::{io::AsyncWriteExt, process::Command, time::timeout};
+use tracing::{debug, info, info_span, warn, Instrument};
+
+use crate::registry::{normalize_host, Credentials};
+
+// Server URL Docker Hub entries are stored under by `docker login`
+const DOCKER_HUB_SERVER: &str = "https://index.docker.io/v1/";
+
+#[derive(Deserialize, Default)]
+struct ConfigFile {
+    #[serde(default)]
+    auths: HashMap<String, AuthEntry>,
+    #[serde(default, rename = "credsStore")]
+    creds_store: Option<String>,
+    #[serde(default, rename = "credHelpers")]
+    cred_helpers: HashMap<String, String>,
+}
+
+#[derive(Deserialize, Default)]
+struct AuthEntry {
+    // base64 of `username:password`
+    #[serde(default)]
+    auth: Option<String>,
+    #[serde(default)]
+    username: Option<String>,
+    #[serde(default)]
+    password: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct HelperResponse {
+    #[serde(rename = "Username")]
+    username: String,
+    #[serde(rename = "Secret")]
+    secret: String,
+}
+
+// Registry logins kept by docker, podman or skopeo, keyed by normalized host
+#[derive(Default)]
+pub struct DockerAuth {
+    auths: HashMap<String, Credentials>,
+    cred_helpers: HashMap<String, String>,
+    creds_store: Option<String>,
+}
+
+impl DockerAuth {
+    // Reads $REGISTRY_AUTH_FILE (containers auth.json) and $DOCKER_CONFIG/config.json
+    // (~/.docker/config.json by default). Entries of the former take precedence.
+    pub async fn from_env() -> anyhow::Result<Self> {
+        let mut files = Vec::new();
+        if let Some(path) = std::env::var("REGISTRY_AUTH_FILE").ok().filter(|p| !p.is_empty()) {
+            files.push((PathBuf::from(path), true));
+        }
+        let docker_dir = std::env::var("DOCKER_CONFIG")
+            .ok()
+            .filter(|p| !p.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| std::env::var("HOME").ok().map(|h| PathBuf::from(h).join(".docker")));
+        if let Some(dir) = docker_dir {
+            files.push((dir.join("config.json"), false));
+        }
+
+        let mut docker_auth = Self::default();
+        for (path, required) in files {
+            let text = match tokio::fs::read_to_string(&path).await {
+                Ok(text) => text,
+                Err(e) if !required && e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => anyhow::bail!("Failed to read {}: {}", path.display(), e),
+            };
+            let config: ConfigFile = serde_json::from_str(&text)
+                .map_err(|e| anyhow::anyhow!("Invalid registry auth file {}: {}", path.display(), e))?;
+            info!(
+                "Loaded registry auth file {} ({} logins, {} credential helpers)",
+                path.display(),
+                config.auths.len(),
+                config.cred_helpers.len() + usize::from(config.creds_store.is_some())
+            );
+            docker_auth.merge(config);
+        }
+        Ok(docker_auth)
+    }
+
+    // Keep existing entries: files are merged from highest to lowest precedence
+    fn merge(&mut self, config: ConfigFile) {
+        for (server, entry) in config.auths {
+            let host = normalize_host(&server);
+            if self.auths.contains_key(&host) {
+                continue;
+            }
+            match entry.credentials() {
+                Some(creds) => {
+                    self.auths.insert(host, creds);
+                }
+                // Entries without a secret only mark hosts handled by credsStore
+                None => debug!("No inline credentials for {}", server),
+            }
+        }
+        for (server, helper) in config.cred_helpers {
+            self.cred_helpers.entry(normalize_host(&server)).or_insert(helper);
+        }
+        if self.creds_store.is_none() {
+            self.creds_store = config.creds_store.filter(|s| !s.is_empty());
+        }
+    }
+
+    // Same order as docker: a per-registry helper, then inline logins, then the
+    // default credential store.
+    pub async fn lookup(&self, registry: &str) -> Option<Credentials> {
+        let host = normalize_host(registry);
+        if let Some(helper) = self.cred_helpers.get(&host) {
+            return run_helper(helper, &host).await;
+        }
+        if let Some(creds) = self.auths.get(&host) {
+            return Some(creds.clone());
+        }
+        match &self.creds_store {
+            Some(store) => run_helper(store, &host).await,
+            None => None,
+        }
+    }
+}
+
+impl AuthEntry {
+    fn credentials(&self) -> Option<Credentials> {
+        if let Some(auth) = self.auth.as_deref().filter(|a| !a.is_empty()) {
+            let decoded = base64::engine::general_purpose::STANDARD.decode(auth).ok()?;
+            let decoded = String::from_utf8(decoded).ok()?;
+            let (username, password) = decoded.split_once(':')?;
+            return Credentials::from_parts(Some(username), Some(password));
+        }
+        Credentials::from_parts(self.username.as_deref(), self.password.as_deref())
+    }
+}
+
+// `docker-credential-<helper> get`: server URL on stdin, JSON credentials on stdout
+async fn run_helper(helper: &str, host: &str) -> Option<Credentials> {
+    let program = format!("docker-credential-{}", helper);
+    let server = if host == "docker.io" { DOCKER_HUB_SERVER } else { host };
+
+    let mut child = match Command::new(&program)
+        .arg("get")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(e) => {
+            warn!("Failed to run {}: {}", program, e);
+            return None;
+        }
+    };
+    if let Some(mut stdin) = child.stdin.take() {
+        if let Err(e) = stdin.write_all(server.as_bytes()).await {
+            warn!("Failed to write to {}: {}", program, e);
+            return None;
+        }
+    }
+
+    let wait = child
+        .wait_with_output()
+        .instrument(info_span!("credential_helper", helper = %program));
+    let output = match timeout(Duration::from_secs(10), wait).await {
+        Ok(Ok(output)) => output,
+        Ok(Err(e)) => {
+            warn!("{} failed: {}", program, e);
+            return None;
+        }
+        Err(_) => {
+            warn!("{} timed out", program);
+            return None;
+        }
+    };
+    if !output.status.success() {
+        // "credentials not found in native keychain" is the usual answer for unknown hosts
+        debug!(
+            "{} has no credentials for {}: {}",
+            program,
+            server,
+            String::from_utf8_lossy(&output.stdout).trim()
+        );
+        return None;
+    }
+
+    match serde_json::from_slice::<HelperResponse>(&output.stdout) {
+        // Identity tokens need an OAuth exchange the backend does not implement
+        Ok(response) if response.username == "<token>" => {
+            warn!("{} returned an identity token for {}, which is not supported", program, server);
+            None
+        }
+        Ok(response) => {
+            debug!("Using credentials from {} for {}", program, server);
+            Credentials::from_parts(Some(&response.username), Some(&response.secret))
+        }
+        Err(e) => {
+            warn!("Invalid response from {}: {}", program, e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{os::unix::fs::PermissionsExt, path::Path, sync::OnceLock};
+
+    // Echoes its own name back so tests can tell which helper answered, and logs every
+    // server it is asked about next to itself
+    const HELPER: &str = r#"#!/bin/sh
+[ "$1" = get ] || exit 2
+server=$(cat)
+echo "$server" >> "$0.log"
+case "$server" in
+    https://index.docker.io/v1/) echo '{"ServerURL":"https://index.docker.io/v1/","Username":"hub","Secret":"s"}' ;;
+    tokens.example) echo '{"Username":"<token>","Secret":"refresh"}' ;;
+    ghcr.io|quay.io) printf '{"Username":"%s","Secret":"s"}' "$(basename "$0")" ;;
+    *) echo "credentials not found in native keychain"; exit 1 ;;
+esac
+"#;
+
+    // All stubs are written before PATH changes, so no helper is ever spawned while open for writing
+    fn helpers() -> &'static Path {
+        static DIR: OnceLock<tempfile::TempDir> = OnceLock::new();
+        DIR.get_or_init(|| {
+            let dir = tempfile::tempdir().unwrap();
+            for name in ["order-helper", "order-store", "protocol"] {
+                let path = dir.path().join(format!("docker-credential-{}", name));
+                std::fs::write(&path, HELPER).unwrap();
+                std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+            }
+            let path = std::env::var("PATH").unwrap_or_default();
+            std::env::set_var("PATH", format!("{}:{}", dir.path().display(), path));
+            dir
+        })
+        .path()
+    }
+
+    fn auth(text: &str) -> DockerAuth {
+        let mut docker_auth = DockerAuth::default();
+        docker_auth.merge(serde_json::from_str(text).unwrap());
+        docker_auth
+    }
+
+    fn basic(username: &str, password: &str) -> String {
+        base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", username, password))
+    }
+
+    #[test]
+    fn merge_keeps_the_first_file() {
+        // REGISTRY_AUTH_FILE is merged before config.json
+        let first = format!(
+            r#"{{"auths": {{"ghcr.io": {{"auth": "{}"}}}}, "credsStore": "first"}}"#,
+            basic("first", "one")
+        );
+        let second = format!(
+            r#"{{
+                "auths": {{
+                    "https://ghcr.io": {{"username": "second", "password": "two"}},
+                    "https://index.docker.io/v1/": {{"auth": "{}"}},
+                    "quay.io": {{}}
+                }},
+                "credHelpers": {{"quay.io": "second", "GHCR.io": "second"}},
+                "credsStore": "second"
+            }}"#,
+            basic("hub", "pw")
+        );
+        let mut docker_auth = auth(&first);
+        docker_auth.merge(serde_json::from_str(&second).unwrap());
+
+        assert_eq!(docker_auth.auths["ghcr.io"].username, "first");
+        assert_eq!(docker_auth.auths["ghcr.io"].password, "one");
+        // `docker login` stores Docker Hub under its v1 index URL
+        assert_eq!(docker_auth.auths["docker.io"].username, "hub");
+        assert!(!docker_auth.auths.contains_key("quay.io"));
+        assert_eq!(docker_auth.cred_helpers["quay.io"], "second");
+        assert_eq!(docker_auth.cred_helpers["ghcr.io"], "second");
+        assert_eq!(docker_auth.creds_store.as_deref(), Some("first"));
+
+        // An empty credsStore does not shadow a later one
+        let mut docker_auth = auth(r#"{"credsStore": ""}"#);
+        docker_auth.merge(serde_json::from_str(r#"{"credsStore": "second"}"#).unwrap());
+        assert_eq!(docker_auth.creds_store.as_deref(), Some("second"));
+    }
+
+    #[test]
+    fn reads_inline_logins() {
+        let cases = [
+            (format!(r#"{{"auth": "{}"}}"#, basic("user", "pa:ss")), Some(("user", "pa:ss"))),
+            (r#"{"username": "user", "password": "pass"}"#.to_string(), Some(("user", "pass"))),
+            (format!(r#"{{"auth": "{}"}}"#, basic("user", "")), None),
+            (r#"{"auth": "not base64"}"#.to_string(), None),
+            (r#"{"username": "user"}"#.to_string(), None),
+            ("{}".to_string(), None),
+        ];
+        for (entry, expected) in cases {
+            let creds = serde_json::from_str::<AuthEntry>(&entry).unwrap().credentials();
+            let creds = creds.as_ref().map(|c| (c.username.as_str(), c.password.as_str()));
+            assert_eq!(creds, expected, "{}", entry);
+        }
+    }
+
+    #[tokio::test]
+    async fn lookup_prefers_helpers_then_logins_then_store() {
+        helpers();
+        let docker_auth = auth(&format!(
+            r#"{{
+                "auths": {{"ghcr.io": {{"auth": "{0}"}}, "registry.example": {{"auth": "{0}"}}}},
+                "credHelpers": {{"ghcr.io": "order-helper", "missing.example": "absent"}},
+                "credsStore": "order-store"
+            }}"#,
+            basic("inline", "pw")
+        ));
+
+        let user = |creds: Option<Credentials>| creds.map(|c| c.username);
+        assert_eq!(user(docker_auth.lookup("ghcr.io").await).as_deref(), Some("docker-credential-order-helper"));
+        assert_eq!(user(docker_auth.lookup("registry.example").await).as_deref(), Some("inline"));
+        assert_eq!(user(docker_auth.lookup("quay.io").await).as_deref(), Some("docker-credential-order-store"));
+        // A configured helper is authoritative, even when it cannot be run
+        assert!(docker_auth.lookup("missing.example").await.is_none());
+        assert!(docker_auth.lookup("unknown.example").await.is_none());
+        assert!(docker_auth.lookup("tokens.example").await.is_none());
+        assert!(DockerAuth::default().lookup("ghcr.io").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn helpers_receive_the_server_on_stdin() {
+        let log = helpers().join("docker-credential-protocol.log");
+        let docker_auth = auth(r#"{"credsStore": "protocol"}"#);
+
+        for registry in ["docker.io", "registry-1.docker.io", "https://ghcr.io/v2/"] {
+            assert!(docker_auth.lookup(registry).await.is_some(), "{}", registry);
+        }
+        let hub = docker_auth.lookup("index.docker.io").await.unwrap();
+        assert_eq!((hub.username.as_str(), hub.password.as_str()), ("hub", "s"));
+
+        let servers = std::fs::read_to_string(log).unwrap();
+        assert_eq!(
+            servers.lines().collect::<Vec<_>>(),
+            [DOCKER_HUB_SERVER, DOCKER_HUB_SERVER, "ghcr.io", DOCKER_HUB_SERVER]
+        );
+    }
+}
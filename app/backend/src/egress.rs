@@ -0,0 +1,413 @@
+use ipnet::IpNet;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+    sync::Arc,
+};
+use tracing::warn;
+
+// Ranges that must never be reachable through user-supplied URLs unless
+// explicitly allowed: loopback, private, link-local (cloud metadata lives at
+// 169.254.169.254), CGNAT, multicast and reserved space.
+const DEFAULT_DENIED: &[&str] = &[
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.0.0.0/24",
+    "192.168.0.0/16",
+    "198.18.0.0/15",
+    "224.0.0.0/4",
+    "240.0.0.0/4",
+    "::/128",
+    "::1/128",
+    "fc00::/7",
+    "fe80::/10",
+    "ff00::/8",
+];
+
+#[derive(thiserror::Error, Debug)]
+pub enum EgressError {
+    #[error("destination {0} is not allowed by the egress policy")]
+    Denied(String),
+    #[error("unsupported URL scheme: {0}")]
+    Scheme(String),
+    #[error("failed to resolve {0}: {1}")]
+    Resolve(String, std::io::Error),
+}
+
+// Either a CIDR range or a host pattern (`registry.internal`, `*.corp.example`)
+#[derive(Clone, Debug)]
+enum Rule {
+    Net(IpNet),
+    Host(String),
+}
+
+impl Rule {
+    fn parse(entry: &str) -> Self {
+        let entry = entry.trim().to_lowercase();
+        if let Ok(net) = IpNet::from_str(&entry) {
+            return Self::Net(net);
+        }
+        if let Ok(ip) = IpAddr::from_str(&entry) {
+            return Self::Net(IpNet::from(ip));
+        }
+        Self::Host(entry)
+    }
+
+    fn matches_host(&self, host: &str) -> bool {
+        match self {
+            Self::Host(pattern) => match pattern.strip_prefix("*.") {
+                Some(suffix) => host.ends_with(&format!(".{}", suffix)),
+                None => host == pattern,
+            },
+            Self::Net(_) => false,
+        }
+    }
+
+    fn matches_ip(&self, ip: IpAddr) -> bool {
+        match self {
+            Self::Net(net) => net.contains(&ip),
+            Self::Host(_) => false,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct EgressPolicy {
+    allow: Vec<Rule>,
+    deny: Vec<Rule>,
+    // Only hosts matched by an allow rule may be contacted
+    strict: bool,
+    deny_private: bool,
+}
+
+impl EgressPolicy {
+    // EGRESS_ALLOW / EGRESS_DENY: comma-separated CIDRs or host patterns.
+    // Deny rules win over allow rules; allowed hosts and ranges bypass the
+    // default private-range block.
+    pub fn from_env() -> Self {
+        let list = |var: &str| std::env::var(var).unwrap_or_default();
+        let flag = |var: &str| {
+            std::env::var(var)
+                .map(|v| v == "true" || v == "1")
+                .unwrap_or(false)
+        };
+        Self::new(
+            &list("EGRESS_ALLOW"),
+            &list("EGRESS_DENY"),
+            flag("EGRESS_STRICT"),
+            flag("EGRESS_ALLOW_PRIVATE"),
+        )
+    }
+
+    fn new(allow: &str, deny: &str, strict: bool, allow_private: bool) -> Self {
+        let rules = |list: &str| -> Vec<Rule> {
+            list.split(',')
+                .filter(|e| !e.trim().is_empty())
+                .map(Rule::parse)
+                .collect()
+        };
+        Self {
+            allow: rules(allow),
+            deny: rules(deny),
+            strict,
+            deny_private: !allow_private,
+        }
+    }
+
+    fn host_allowed(&self, host: &str) -> bool {
+        self.allow.iter().any(|r| r.matches_host(host))
+    }
+
+    fn check_host_name(&self, host: &str) -> Result<(), EgressError> {
+        if self.deny.iter().any(|r| r.matches_host(host)) {
+            return Err(EgressError::Denied(host.to_string()));
+        }
+        if self.strict && !self.host_allowed(host) {
+            return Err(EgressError::Denied(host.to_string()));
+        }
+        Ok(())
+    }
+
+    // Check a resolved address; `host` is the name it was resolved from
+    pub fn check_ip(&self, host: &str, ip: IpAddr) -> Result<(), EgressError> {
+        // Judge IPv6 addresses that reach an IPv4 host by their IPv4 form
+        let ip = match ip {
+            IpAddr::V6(v6) => embedded_ipv4(v6).map(IpAddr::V4).unwrap_or(ip),
+            v4 => v4,
+        };
+        let denied = || EgressError::Denied(format!("{} ({})", host, ip));
+
+        if self.deny.iter().any(|r| r.matches_ip(ip)) {
+            return Err(denied());
+        }
+        if self.host_allowed(host) || self.allow.iter().any(|r| r.matches_ip(ip)) {
+            return Ok(());
+        }
+        if self.deny_private && is_default_denied(ip) {
+            return Err(denied());
+        }
+        Ok(())
+    }
+
+    // Validate a URL before it is requested or followed as a redirect. Host names
+    // are checked again at connect time by the resolver; IP literals never reach
+    // the resolver, so they are fully checked here.
+    pub fn check_url(&self, url: &url::Url) -> Result<(), EgressError> {
+        match url.scheme() {
+            "http" | "https" => {}
+            other => return Err(EgressError::Scheme(other.to_string())),
+        }
+        match url.host() {
+            Some(url::Host::Domain(host)) => self.check_host_name(&host.to_lowercase()),
+            Some(url::Host::Ipv4(ip)) => self.check_ip(&ip.to_string(), IpAddr::V4(ip)),
+            Some(url::Host::Ipv6(ip)) => self.check_ip(&ip.to_string(), IpAddr::V6(ip)),
+            None => Err(EgressError::Denied(url.to_string())),
+        }
+    }
+
+    // Pre-flight check for destinations contacted by skopeo and helm. The
+    // subprocesses resolve the host themselves, so this cannot pin the address.
+    pub async fn check_registry_host(&self, registry: &str) -> Result<(), EgressError> {
+        let url = url::Url::parse(&crate::registry::base_url(registry))
+            .map_err(|_| EgressError::Denied(registry.to_string()))?;
+        self.check_url(&url)?;
+        if let Some(url::Host::Domain(host)) = url.host() {
+            let port = url.port_or_known_default().unwrap_or(443);
+            let addrs = tokio::net::lookup_host((host, port))
+                .await
+                .map_err(|e| EgressError::Resolve(host.to_string(), e))?;
+            for addr in addrs {
+                self.check_ip(host, addr.ip())?;
+            }
+        }
+        Ok(())
+    }
+
+    // Redirect policy re-checking every hop
+    pub fn redirect_policy(self: &Arc<Self>) -> reqwest::redirect::Policy {
+        let policy = self.clone();
+        reqwest::redirect::Policy::custom(move |attempt| {
+            if attempt.previous().len() >= 10 {
+                return attempt.error("too many redirects");
+            }
+            match policy.check_url(attempt.url()) {
+                Ok(()) => attempt.follow(),
+                Err(e) => {
+                    warn!("Blocked redirect to {}: {}", attempt.url(), e);
+                    attempt.error(e)
+                }
+            }
+        })
+    }
+}
+
+// The IPv4 address carried by IPv4-mapped (::ffff:a.b.c.d), IPv4-compatible
+// (::a.b.c.d), NAT64 (64:ff9b::/96) and 6to4 (2002::/16) addresses
+fn embedded_ipv4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    if let Some(v4) = ip.to_ipv4_mapped() {
+        return Some(v4);
+    }
+    let s = ip.segments();
+    let low = |hi: u16, lo: u16| Ipv4Addr::from((u32::from(hi) << 16) | u32::from(lo));
+    match s {
+        // :: and ::1 are not IPv4-compatible addresses
+        [0, 0, 0, 0, 0, 0, 0, 0 | 1] => None,
+        [0, 0, 0, 0, 0, 0, hi, lo] => Some(low(hi, lo)),
+        [0x64, 0xff9b, 0, 0, 0, 0, hi, lo] => Some(low(hi, lo)),
+        [0x2002, hi, lo, ..] => Some(low(hi, lo)),
+        _ => None,
+    }
+}
+
+fn is_default_denied(ip: IpAddr) -> bool {
+    static NETS: once_cell::sync::Lazy<Vec<IpNet>> = once_cell::sync::Lazy::new(|| {
+        DEFAULT_DENIED.iter().map(|n| n.parse().unwrap()).collect()
+    });
+    NETS.iter().any(|net| net.contains(&ip))
+}
+
+// DNS resolver for the shared HTTP client. Every connection resolves the host
+// exactly once here and connects to the addresses checked against the policy,
+// so a second, rebinding answer can never be used.
+pub struct PolicyResolver {
+    policy: Arc<EgressPolicy>,
+}
+
+impl PolicyResolver {
+    pub fn new(policy: Arc<EgressPolicy>) -> Self {
+        Self { policy }
+    }
+}
+
+impl Resolve for PolicyResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let policy = self.policy.clone();
+        Box::pin(async move {
+            let host = name.as_str().to_lowercase();
+            policy.check_host_name(&host)?;
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), 0))
+                .await
+                .map_err(|e| EgressError::Resolve(host.clone(), e))?
+                .collect();
+            for addr in &addrs {
+                if let Err(e) = policy.check_ip(&host, addr.ip()) {
+                    warn!("Blocked connection: {}", e);
+                    return Err(e.into());
+                }
+            }
+            let addrs: Addrs = Box::new(addrs.into_iter());
+            Ok(addrs)
+        })
+    }
+}
+
+// Whether a request failed because the egress policy refused the destination
+pub fn is_denied(err: &(dyn std::error::Error + 'static)) -> bool {
+    let mut source = Some(err);
+    while let Some(e) = source {
+        if matches!(e.downcast_ref::<EgressError>(), Some(EgressError::Denied(_))) {
+            return true;
+        }
+        source = e.source();
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> EgressPolicy {
+        EgressPolicy::new("", "", false, false)
+    }
+
+    fn check(policy: &EgressPolicy, ip: &str) -> bool {
+        policy.check_ip("host.example", ip.parse().unwrap()).is_ok()
+    }
+
+    #[test]
+    fn denies_default_ranges() {
+        let denied = [
+            "0.1.2.3",
+            "10.1.2.3",
+            "100.64.0.1",
+            "127.0.0.1",
+            "169.254.169.254",
+            "172.16.0.1",
+            "192.0.0.8",
+            "192.168.1.1",
+            "198.18.0.1",
+            "224.0.0.1",
+            "240.0.0.1",
+            "::",
+            "::1",
+            "fc00::1",
+            "fe80::1",
+            "ff02::1",
+            // IPv4 hosts behind IPv6 forms
+            "::ffff:10.0.0.1",
+            "::10.0.0.1",
+            "::169.254.169.254",
+            "64:ff9b::a9fe:a9fe",
+            "64:ff9b::127.0.0.1",
+            "2002:a9fe:a9fe::1",
+            "2002:c0a8:0101::",
+        ];
+        for ip in denied {
+            assert!(!check(&policy(), ip), "{} should be denied", ip);
+        }
+        for ip in ["8.8.8.8", "140.82.112.3", "2606:4700::1111", "64:ff9b::808:808", "2002:808:808::1"] {
+            assert!(check(&policy(), ip), "{} should be allowed", ip);
+        }
+    }
+
+    #[test]
+    fn allow_private_disables_default_ranges() {
+        let policy = EgressPolicy::new("", "", false, true);
+        assert!(check(&policy, "10.0.0.1"));
+        assert!(check(&policy, "169.254.169.254"));
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let policy = EgressPolicy::new("10.0.0.0/8,registry.internal", "10.1.0.0/16,bad.internal", false, false);
+        assert!(check(&policy, "10.2.0.1"));
+        assert!(!check(&policy, "10.1.0.1"));
+        // An allowed host name bypasses the private-range block, not the deny list
+        assert!(policy.check_ip("registry.internal", "192.168.1.1".parse().unwrap()).is_ok());
+        assert!(policy.check_ip("registry.internal", "10.1.0.1".parse().unwrap()).is_err());
+        let url = url::Url::parse("https://bad.internal/v2/").unwrap();
+        assert!(policy.check_url(&url).is_err());
+        // Mapped forms of a denied address stay denied
+        assert!(!check(&policy, "::ffff:10.1.0.1"));
+        assert!(!check(&policy, "64:ff9b::a01:1"));
+    }
+
+    #[test]
+    fn strict_mode_requires_an_allow_rule() {
+        let policy = EgressPolicy::new("ghcr.io,*.corp.example", "", true, false);
+        let allowed = |u: &str| policy.check_url(&url::Url::parse(u).unwrap()).is_ok();
+        assert!(allowed("https://ghcr.io/v2/"));
+        assert!(allowed("https://charts.corp.example/index.yaml"));
+        assert!(!allowed("https://corp.example/index.yaml"));
+        assert!(!allowed("https://docker.io/v2/"));
+        assert!(!allowed("https://ghcr.io.evil.example/v2/"));
+    }
+
+    #[test]
+    fn check_url_checks_literals_and_schemes() {
+        let denied = |u: &str| policy().check_url(&url::Url::parse(u).unwrap()).is_err();
+        assert!(denied("http://169.254.169.254/latest/meta-data/"));
+        assert!(denied("http://[::ffff:a9fe:a9fe]/"));
+        assert!(denied("http://[64:ff9b::a9fe:a9fe]/"));
+        assert!(denied("http://2130706433/"));
+        assert!(denied("file:///etc/passwd"));
+        assert!(denied("ftp://example.com/"));
+        assert!(!denied("https://ghcr.io/v2/"));
+    }
+
+    #[tokio::test]
+    async fn resolver_refuses_denied_addresses() {
+        let resolve = |policy: EgressPolicy| {
+            PolicyResolver::new(Arc::new(policy)).resolve(Name::from_str("localhost").unwrap())
+        };
+        let err = resolve(policy()).await.err().unwrap();
+        assert!(is_denied(&*err), "{}", err);
+        assert!(resolve(EgressPolicy::new("localhost", "", false, false)).await.is_ok());
+        let err = resolve(EgressPolicy::new("", "localhost", false, true)).await.err().unwrap();
+        assert!(is_denied(&*err), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn redirects_are_checked_on_every_hop() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // Answers every request with a redirect to the cloud metadata endpoint
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut buf = [0u8; 1024];
+                let _ = socket.read(&mut buf).await;
+                let response = "HTTP/1.1 302 Found\r\nLocation: http://169.254.169.254/latest/meta-data/\r\n\
+                                Content-Length: 0\r\nConnection: close\r\n\r\n";
+                let _ = socket.write_all(response.as_bytes()).await;
+            }
+        });
+
+        let policy = Arc::new(EgressPolicy::new("127.0.0.1", "", false, false));
+        let client = reqwest::Client::builder()
+            .redirect(policy.redirect_policy())
+            .dns_resolver(Arc::new(PolicyResolver::new(policy.clone())))
+            .build()
+            .unwrap();
+        let err = client.get(format!("http://{}/index.yaml", addr)).send().await.unwrap_err();
+        assert!(err.is_redirect(), "{}", err);
+        assert!(is_denied(&err), "{}", err);
+    }
+}
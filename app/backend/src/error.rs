@@ -0,0 +1,294 @@
+use axum::{
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::{egress, failure::Failure, policy, registry::RegistryError, request_id, vault};
+
+// Errors returned by the API. Every variant renders the same JSON body, keyed
+// by a stable `code` clients can branch on:
+//
+//   {"code": "upstream_not_found", "message": "...", "upstreamStatus": 404,
+//    "retryAfter": 30, "requestId": "..."}
+//
+// `upstreamStatus` and `retryAfter` are only present when known. Failed skopeo
+// and helm runs also carry the sanitized tool error as `detail` when the
+// caller passed `detail=true`.
+#[derive(Debug, thiserror::Error)]
+pub enum ApiError {
+    #[error("{0}")]
+    BadRequest(String),
+    // The caller is not authenticated
+    #[error("{0}")]
+    Unauthenticated(String),
+    // The caller lacks a role
+    #[error("{0}")]
+    Forbidden(String),
+    #[error("Denied by pull {0}")]
+    PolicyDenied(policy::Violation),
+    #[error("{0}")]
+    EgressDenied(String),
+    #[error("{0}")]
+    SignatureInvalid(String),
+    #[error("{0}")]
+    ChartVerificationFailed(String),
+    #[error("{0}")]
+    NotFound(String),
+    // A feature the request needs is not configured
+    #[error("{0}")]
+    NotEnabled(String),
+    #[error("{0}")]
+    Unprocessable(String),
+    // Our own rate limit
+    #[error("Too many requests")]
+    RateLimited { retry_after: u64 },
+    // The registry or chart repository answered with an error status.
+    // 401, 403, 404 and 429 keep their status, anything else becomes 502.
+    #[error("{message}")]
+    Upstream {
+        status: StatusCode,
+        message: String,
+        retry_after: Option<u64>,
+    },
+    #[error("{0}")]
+    UpstreamUnreachable(String),
+    #[error("{0}")]
+    UpstreamTls(String),
+    #[error("{0}")]
+    UpstreamDns(String),
+    #[error("{0}")]
+    UpstreamRefused(String),
+    // The image has no manifest for the requested platform
+    #[error("{0}")]
+    PlatformMismatch(String),
+    #[error("{0}")]
+    InvalidVersion(String),
+    #[error("{0}")]
+    UpstreamInvalid(String),
+    #[error("{0}")]
+    Timeout(String),
+    #[error("{0} command not found")]
+    ToolNotFound(&'static str),
+    #[error("{0}")]
+    ToolFailed(String),
+    #[error("Server shutting down")]
+    ShuttingDown,
+    #[error("{0}")]
+    Internal(String),
+}
+
+// Also the `Error` schema of the OpenAPI document
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+#[schema(as = Error)]
+pub struct Body<'a> {
+    code: &'static str,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    upstream_status: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retry_after: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<&'a str>,
+}
+
+impl ApiError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::BadRequest(_) | Self::InvalidVersion(_) => StatusCode::BAD_REQUEST,
+            Self::Unauthenticated(_) => StatusCode::UNAUTHORIZED,
+            Self::Forbidden(_) | Self::PolicyDenied(_) | Self::EgressDenied(_) | Self::SignatureInvalid(_) => {
+                StatusCode::FORBIDDEN
+            }
+            Self::NotFound(_) => StatusCode::NOT_FOUND,
+            Self::NotEnabled(_) | Self::ToolNotFound(_) => StatusCode::NOT_IMPLEMENTED,
+            Self::ChartVerificationFailed(_) | Self::Unprocessable(_) | Self::PlatformMismatch(_) => {
+                StatusCode::UNPROCESSABLE_ENTITY
+            }
+            Self::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            Self::Upstream { status, .. } => match *status {
+                StatusCode::UNAUTHORIZED
+                | StatusCode::FORBIDDEN
+                | StatusCode::NOT_FOUND
+                | StatusCode::TOO_MANY_REQUESTS => *status,
+                _ => StatusCode::BAD_GATEWAY,
+            },
+            Self::UpstreamUnreachable(_)
+            | Self::UpstreamTls(_)
+            | Self::UpstreamDns(_)
+            | Self::UpstreamRefused(_)
+            | Self::UpstreamInvalid(_)
+            | Self::ToolFailed(_) => StatusCode::BAD_GATEWAY,
+            Self::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            Self::ShuttingDown => StatusCode::SERVICE_UNAVAILABLE,
+            Self::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::BadRequest(_) => "bad_request",
+            Self::Unauthenticated(_) => "unauthenticated",
+            Self::Forbidden(_) => "forbidden",
+            Self::PolicyDenied(_) => "policy_denied",
+            Self::EgressDenied(_) => "egress_denied",
+            Self::SignatureInvalid(_) => "signature_invalid",
+            Self::ChartVerificationFailed(_) => "chart_verification_failed",
+            Self::NotFound(_) => "not_found",
+            Self::NotEnabled(_) => "not_enabled",
+            Self::Unprocessable(_) => "unprocessable",
+            Self::RateLimited { .. } => "rate_limited",
+            Self::Upstream { status, .. } => match *status {
+                StatusCode::UNAUTHORIZED => "upstream_unauthorized",
+                StatusCode::FORBIDDEN => "upstream_forbidden",
+                StatusCode::NOT_FOUND => "upstream_not_found",
+                StatusCode::TOO_MANY_REQUESTS => "upstream_rate_limited",
+                _ => "upstream_error",
+            },
+            Self::UpstreamUnreachable(_) => "upstream_unreachable",
+            Self::UpstreamTls(_) => "upstream_tls_error",
+            Self::UpstreamDns(_) => "upstream_dns_error",
+            Self::UpstreamRefused(_) => "upstream_connection_refused",
+            Self::PlatformMismatch(_) => "platform_mismatch",
+            Self::InvalidVersion(_) => "invalid_version",
+            Self::UpstreamInvalid(_) => "upstream_invalid_response",
+            Self::Timeout(_) => "timeout",
+            Self::ToolNotFound(_) => "tool_not_found",
+            Self::ToolFailed(_) => "tool_failed",
+            Self::ShuttingDown => "shutting_down",
+            Self::Internal(_) => "internal",
+        }
+    }
+
+    fn retry_after(&self) -> Option<u64> {
+        match self {
+            Self::RateLimited { retry_after } => Some(*retry_after),
+            Self::Upstream { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+
+    // An upstream error status, with a message naming what was not found on 404
+    pub fn upstream(status: StatusCode, not_found: &str, retry_after: Option<u64>) -> Self {
+        let message = match status {
+            StatusCode::UNAUTHORIZED => "Unauthorized - please check your credentials".to_string(),
+            StatusCode::FORBIDDEN => "Access denied to registry".to_string(),
+            StatusCode::NOT_FOUND => not_found.to_string(),
+            StatusCode::TOO_MANY_REQUESTS => "Registry rate limit exceeded".to_string(),
+            _ => format!("Registry returned {}", status),
+        };
+        Self::Upstream {
+            status,
+            message,
+            retry_after,
+        }
+    }
+
+    // A registry API failure, with the message used when the target is missing
+    pub fn registry(e: &RegistryError, not_found: &str) -> Self {
+        match e {
+            RegistryError::Connect(e) if egress::is_denied(e) => {
+                Self::EgressDenied("Destination not allowed by egress policy".to_string())
+            }
+            RegistryError::Connect(_) => Self::UpstreamUnreachable("Failed to connect to registry".to_string()),
+            RegistryError::Status(status) => Self::upstream(*status, not_found, None),
+            RegistryError::TooManyRequests { retry_after } => {
+                Self::upstream(StatusCode::TOO_MANY_REQUESTS, not_found, *retry_after)
+            }
+            RegistryError::InvalidResponse(_) => Self::UpstreamInvalid("Invalid registry response".to_string()),
+        }
+    }
+
+    // A failed skopeo or helm run, classified from its stderr. `not_found`
+    // names the missing image or chart, `failed` the operation.
+    pub fn tool(failure: Failure, not_found: &str, failed: &str) -> Self {
+        match failure {
+            Failure::RateLimited => Self::upstream(StatusCode::TOO_MANY_REQUESTS, not_found, None),
+            Failure::Tls => Self::UpstreamTls("TLS connection to registry failed".to_string()),
+            Failure::Dns => Self::UpstreamDns("Failed to resolve registry".to_string()),
+            Failure::ConnectionRefused => Self::UpstreamRefused("Registry refused the connection".to_string()),
+            Failure::PlatformMismatch => Self::PlatformMismatch("No image for the requested platform".to_string()),
+            Failure::InvalidVersion => Self::InvalidVersion("Invalid reference or version".to_string()),
+            Failure::Unauthorized => Self::upstream(StatusCode::UNAUTHORIZED, not_found, None),
+            Failure::Denied => Self::upstream(StatusCode::FORBIDDEN, not_found, None),
+            Failure::NotFound => Self::upstream(StatusCode::NOT_FOUND, not_found, None),
+            Failure::Other => Self::ToolFailed(failed.to_string()),
+        }
+    }
+
+    // The JSON response, with the sanitized tool error when one is given
+    pub fn into_response_with(self, detail: Option<String>) -> Response {
+        let status = self.status();
+        let retry_after = self.retry_after();
+        let request_id = request_id::current();
+        let body = Body {
+            code: self.code(),
+            message: self.to_string(),
+            upstream_status: match &self {
+                Self::Upstream { status, .. } => Some(status.as_u16()),
+                _ => None,
+            },
+            retry_after,
+            detail,
+            request_id: request_id.as_deref(),
+        };
+        let mut response = (status, Json(body)).into_response();
+        let headers = response.headers_mut();
+        if let Some(seconds) = retry_after {
+            headers.insert(header::RETRY_AFTER, HeaderValue::from(seconds));
+        }
+        match &self {
+            Self::Unauthenticated(_) => {
+                headers.insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+            }
+            Self::PolicyDenied(v) => {
+                if let Ok(val) = HeaderValue::from_str(&format!("{}/{}", v.policy, v.rule)) {
+                    headers.insert("X-Policy-Rule", val);
+                }
+            }
+            _ => {}
+        }
+        response
+    }
+}
+
+impl From<policy::Violation> for ApiError {
+    fn from(v: policy::Violation) -> Self {
+        Self::PolicyDenied(v)
+    }
+}
+
+impl From<&egress::EgressError> for ApiError {
+    fn from(e: &egress::EgressError) -> Self {
+        match e {
+            egress::EgressError::Resolve(host, _) => {
+                Self::UpstreamDns(format!("Failed to resolve registry: {}", host))
+            }
+            _ => Self::EgressDenied("Registry not allowed by egress policy".to_string()),
+        }
+    }
+}
+
+impl From<vault::VaultError> for ApiError {
+    fn from(e: vault::VaultError) -> Self {
+        match e {
+            vault::VaultError::NotFound(_) => Self::NotFound(e.to_string()),
+            vault::VaultError::RegistryMismatch(..) => Self::Forbidden(e.to_string()),
+            _ => {
+                tracing::error!("Credential store error: {}", e);
+                Self::Internal(e.to_string())
+            }
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        self.into_response_with(None)
+    }
+}
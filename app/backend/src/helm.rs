@@ -0,0 +1,215 @@
+use std::{
+    path::{Path, PathBuf},
+    process::Stdio,
+};
+use tokio::{io::AsyncWriteExt, process::Command};
+use uuid::Uuid;
+
+use crate::registry::Credentials;
+
+// Provenance details printed by `helm pull --verify` on success
+#[derive(Debug, Default)]
+pub struct Verification {
+    pub signed_by: Option<String>,
+    pub fingerprint: Option<String>,
+    pub hash: Option<String>,
+}
+
+impl Verification {
+    pub fn parse(stdout: &str) -> Self {
+        let mut verification = Self::default();
+        for line in stdout.lines() {
+            let line = line.trim();
+            if let Some(v) = line.strip_prefix("Signed by:") {
+                verification.signed_by.get_or_insert_with(|| v.trim().to_string());
+            } else if let Some(v) = line.strip_prefix("Using Key With Fingerprint:") {
+                verification.fingerprint = Some(v.trim().to_string());
+            } else if let Some(v) = line.strip_prefix("Chart Hash Verified:") {
+                verification.hash = Some(v.trim().to_string());
+            }
+        }
+        verification
+    }
+}
+
+// Manifest digest printed by `helm pull` for OCI charts ("Digest: sha256:...")
+pub fn parse_digest(stdout: &str) -> Option<String> {
+    stdout
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("Digest:"))
+        .map(|d| d.trim().to_string())
+        .filter(|d| !d.is_empty())
+}
+
+// Errors of helm's provenance checks: missing .prov, unknown key, bad signature
+// and hash mismatch
+const VERIFICATION_ERRORS: [&str; 5] = [
+    "openpgp: signature made by unknown entity",
+    "openpgp: invalid signature",
+    "failed to verify",
+    "sha256 sum does not match",
+    "failed to fetch provenance",
+];
+
+// Whether a failed `helm pull --verify` was rejected by provenance checks rather
+// than by the registry
+pub fn is_verification_failure(stderr: &str) -> bool {
+    // Go's TLS errors also read "failed to verify"
+    let stderr = stderr.to_lowercase().replace("failed to verify certificate", "");
+    VERIFICATION_ERRORS.iter().any(|e| stderr.contains(e))
+}
+
+// Isolated HELM_REGISTRY_CONFIG holding the login of a single pull, removed when
+// dropped. Never shares the backend user's own registry logins.
+pub struct RegistryConfig {
+    dir: PathBuf,
+}
+
+impl RegistryConfig {
+    pub async fn create() -> std::io::Result<Self> {
+        let dir = crate::janitor::temp_dir().join(format!("helm-registry-{}", Uuid::new_v4()));
+        let mut builder = tokio::fs::DirBuilder::new();
+        #[cfg(unix)]
+        builder.mode(0o700);
+        builder.create(&dir).await?;
+        Ok(Self { dir })
+    }
+
+    pub fn path(&self) -> PathBuf {
+        self.dir.join("config.json")
+    }
+}
+
+impl Drop for RegistryConfig {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+// `helm registry login <registry> --username <user> --password-stdin`
+pub fn login_command(helm_path: &str, registry: &str, username: &str, config: &RegistryConfig) -> Command {
+    let mut cmd = Command::new(helm_path);
+    cmd.arg("registry")
+        .arg("login")
+        .arg(registry)
+        .arg("--username")
+        .arg(username)
+        .arg("--password-stdin")
+        .env("HELM_REGISTRY_CONFIG", config.path());
+    cmd
+}
+
+// Log into `registry`, writing the password to helm's stdin
+pub async fn login(
+    helm_path: &str,
+    registry: &str,
+    creds: &Credentials,
+    config: &RegistryConfig,
+) -> std::io::Result<std::process::Output> {
+    let mut child = login_command(helm_path, registry, &creds.username, config)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(creds.password.as_bytes()).await?;
+    }
+    child.wait_with_output().await
+}
+
+pub struct Pull<'a> {
+    pub reference: &'a str,
+    pub version: Option<&'a str>,
+    // Verify the provenance file against this keyring
+    pub keyring: Option<&'a Path>,
+    pub destination: &'a Path,
+    pub registry_config: Option<&'a RegistryConfig>,
+}
+
+impl Pull<'_> {
+    // `helm pull oci://<reference> [--version] [--verify --keyring] --destination <dir>`
+    pub fn command(&self, helm_path: &str) -> Command {
+        let mut cmd = Command::new(helm_path);
+        // Killed if the pull is abandoned, by the client or at shutdown
+        cmd.arg("pull").kill_on_drop(true);
+        if let Some(config) = self.registry_config {
+            cmd.env("HELM_REGISTRY_CONFIG", config.path());
+        }
+        if let Some(version) = self.version {
+            cmd.arg("--version").arg(version);
+        }
+        if let Some(keyring) = self.keyring {
+            cmd.arg("--verify").arg("--keyring").arg(keyring);
+        }
+        cmd.arg("--destination").arg(self.destination);
+        cmd.arg(format!("oci://{}", self.reference));
+        cmd
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    macro_rules! fixture {
+        ($name:literal) => {
+            ($name, include_str!(concat!("../fixtures/stderr/", $name, ".txt")))
+        };
+    }
+
+    #[test]
+    fn recognizes_verification_failures() {
+        for (name, stderr) in [
+            fixture!("helm-verify-unknown-entity"),
+            fixture!("helm-verify-failed"),
+            fixture!("helm-verify-sum-mismatch"),
+            fixture!("helm-verify-missing-prov"),
+        ] {
+            assert!(is_verification_failure(stderr), "{}", name);
+        }
+        for (name, stderr) in [
+            fixture!("helm-tls"),
+            fixture!("helm-not-found"),
+            fixture!("helm-unauthorized"),
+            fixture!("helm-denied"),
+            fixture!("helm-dns"),
+            fixture!("helm-other"),
+        ] {
+            assert!(!is_verification_failure(stderr), "{}", name);
+        }
+    }
+
+    #[tokio::test]
+    async fn helm_commands_keep_password_out_of_argv() {
+        let creds = Credentials::from_parts(Some("robot"), Some("s3cr3t-pw")).unwrap();
+        let config = RegistryConfig::create().await.unwrap();
+        let destination = std::env::temp_dir();
+        let login = login_command("helm", "ghcr.io", &creds.username, &config);
+        let pull = Pull {
+            reference: "ghcr.io/org/chart",
+            version: Some("1.2.3"),
+            keyring: None,
+            destination: &destination,
+            registry_config: Some(&config),
+        }
+        .command("helm");
+
+        for cmd in [&login, &pull] {
+            let cmd = cmd.as_std();
+            for arg in cmd.get_args() {
+                assert!(!arg.to_string_lossy().contains("s3cr3t-pw"), "secret in argv: {:?}", arg);
+            }
+            let registry_config = cmd
+                .get_envs()
+                .find(|(k, _)| *k == "HELM_REGISTRY_CONFIG")
+                .and_then(|(_, v)| v);
+            assert_eq!(registry_config, Some(config.path().as_os_str()));
+        }
+        assert!(login.as_std().get_args().any(|a| a == "--password-stdin"));
+
+        let dir = config.path().parent().unwrap().to_path_buf();
+        drop(config);
+        assert!(!dir.exists());
+    }
+}
@@ -0,0 +1,392 @@
+use axum::body::{Body, Bytes};
+use base64::Engine;
+use http::{HeaderMap, HeaderValue};
+use hyper::body::{Body as HttpBody, Frame, SizeHint};
+use sha2::{Digest, Sha256};
+use std::{
+    path::{Path, PathBuf},
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncReadExt, ReadBuf},
+    task::JoinHandle,
+};
+use tokio_util::{io::ReaderStream, sync::CancellationToken};
+
+use crate::telemetry::traced_body;
+
+const REPR_DIGEST: &str = "Repr-Digest";
+// RFC 3230 predecessor, still the only one some download tools understand
+const DIGEST: &str = "Digest";
+
+// How often `Tail` looks for more data while the writer is still running
+const TAIL_POLL: Duration = Duration::from_millis(50);
+
+// Clients announce they can read trailers with `TE: trailers`
+pub fn accepts_trailers(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(http::header::TE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|v| v.trim().eq_ignore_ascii_case("trailers"))
+}
+
+// `Repr-Digest: sha-256=:<base64>:` (RFC 9530) and `Digest: sha-256=<base64>`
+pub fn insert_digest(headers: &mut HeaderMap, hash: &[u8]) {
+    let encoded = base64::engine::general_purpose::STANDARD.encode(hash);
+    if let Ok(val) = HeaderValue::from_str(&format!("sha-256=:{}:", encoded)) {
+        headers.insert(REPR_DIGEST, val);
+    }
+    if let Ok(val) = HeaderValue::from_str(&format!("sha-256={}", encoded)) {
+        headers.insert(DIGEST, val);
+    }
+}
+
+// Strong validator from the content hash, `"sha256:<hex>"`
+pub fn insert_etag(headers: &mut HeaderMap, hash: &[u8]) {
+    if let Ok(val) = HeaderValue::from_str(&format!("\"sha256:{}\"", hex::encode(hash))) {
+        headers.insert(http::header::ETAG, val);
+    }
+}
+
+pub fn sha256(data: &[u8]) -> Vec<u8> {
+    Sha256::digest(data).to_vec()
+}
+
+// `<archive>.sha256` in the `sha256sum` format, so `sha256sum -c` checks the
+// archive next to it
+pub fn sidecar(hash: &[u8], filename: &str) -> (HeaderMap, String) {
+    let mut headers = HeaderMap::new();
+    headers.insert(http::header::CONTENT_TYPE, HeaderValue::from_static("text/plain; charset=utf-8"));
+    if let Ok(val) = HeaderValue::from_str(&format!("attachment; filename=\"{}.sha256\"", filename)) {
+        headers.insert(http::header::CONTENT_DISPOSITION, val);
+    }
+    insert_etag(&mut headers, hash);
+    (headers, format!("{}  {}\n", hex::encode(hash), filename))
+}
+
+// `Chart Hash Verified: sha256:<hex>` as printed by `helm pull --verify`
+pub fn parse_sha256(digest: &str) -> Option<Vec<u8>> {
+    let hash = hex::decode(digest.trim().strip_prefix("sha256:")?).ok()?;
+    (hash.len() == 32).then_some(hash)
+}
+
+// Hashes a file while another process writes it, so the digest is ready when
+// the writer exits instead of reading the archive a second time
+pub struct Tail {
+    path: PathBuf,
+    done: CancellationToken,
+    task: JoinHandle<std::io::Result<(Vec<u8>, std::fs::Metadata)>>,
+}
+
+impl Tail {
+    pub fn start(path: &Path) -> Self {
+        let done = CancellationToken::new();
+        Self {
+            path: path.to_path_buf(),
+            task: tokio::spawn(tail(path.to_path_buf(), done.clone())),
+            done,
+        }
+    }
+
+    // Once the writer has exited: the digest of the whole file, or None when the
+    // file was not written in place (replaced or truncated meanwhile)
+    pub async fn finish(&mut self) -> Option<Vec<u8>> {
+        self.done.cancel();
+        let (hash, read) = (&mut self.task).await.ok()?.ok()?;
+        let current = tokio::fs::metadata(&self.path).await.ok()?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            if (read.dev(), read.ino()) != (current.dev(), current.ino()) {
+                return None;
+            }
+        }
+        (read.len() == current.len()).then_some(hash)
+    }
+}
+
+impl Drop for Tail {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn tail(path: PathBuf, done: CancellationToken) -> std::io::Result<(Vec<u8>, std::fs::Metadata)> {
+    let mut file = loop {
+        match File::open(&path).await {
+            Ok(file) => break file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && !done.is_cancelled() => {
+                tokio::time::sleep(TAIL_POLL).await;
+            }
+            Err(e) => return Err(e),
+        }
+    };
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut read = 0u64;
+    loop {
+        // Checked before reading, so an empty read after it is the real end
+        let finished = done.is_cancelled();
+        let n = file.read(&mut buf).await?;
+        if n > 0 {
+            hasher.update(&buf[..n]);
+            read += n as u64;
+            continue;
+        }
+        if finished {
+            break;
+        }
+        tokio::select! {
+            _ = done.cancelled() => {}
+            _ = tokio::time::sleep(TAIL_POLL) => {}
+        }
+    }
+    let meta = file.metadata().await?;
+    if meta.len() != read {
+        return Err(std::io::Error::other("file changed while hashing"));
+    }
+    Ok((hasher.finalize().to_vec(), meta))
+}
+
+pub async fn file_sha256(path: &Path) -> std::io::Result<Vec<u8>> {
+    let mut file = File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hasher.finalize().to_vec())
+}
+
+// Streams a file while hashing it, then sends the digest as trailers. The size
+// is deliberately not exact: with a Content-Length hyper drops trailers.
+pub struct HashingBody {
+    file: File,
+    buf: Box<[u8]>,
+    hasher: Option<Sha256>,
+    size: u64,
+}
+
+impl HashingBody {
+    pub fn new(file: File, size: u64) -> Self {
+        Self {
+            file,
+            buf: vec![0u8; 64 * 1024].into_boxed_slice(),
+            hasher: Some(Sha256::new()),
+            size,
+        }
+    }
+}
+
+impl HttpBody for HashingBody {
+    type Data = Bytes;
+    type Error = std::io::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = &mut *self;
+        if this.hasher.is_none() {
+            return Poll::Ready(None);
+        }
+        let mut buf = ReadBuf::new(&mut this.buf);
+        match Pin::new(&mut this.file).poll_read(cx, &mut buf) {
+            Poll::Ready(Ok(())) if buf.filled().is_empty() => {
+                let hash = this.hasher.take().map(|h| h.finalize()).unwrap_or_default();
+                let mut trailers = HeaderMap::new();
+                insert_digest(&mut trailers, &hash);
+                Poll::Ready(Some(Ok(Frame::trailers(trailers))))
+            }
+            Poll::Ready(Ok(())) => {
+                let chunk = Bytes::copy_from_slice(buf.filled());
+                if let Some(hasher) = this.hasher.as_mut() {
+                    hasher.update(&chunk);
+                }
+                Poll::Ready(Some(Ok(Frame::data(chunk))))
+            }
+            Poll::Ready(Err(e)) => Poll::Ready(Some(Err(e))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.hasher.is_none()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        let mut hint = SizeHint::new();
+        hint.set_lower(self.size);
+        hint
+    }
+}
+
+// Body for an artifact on disk. Its SHA-256 goes in the headers with an ETag
+// when already known, else in trailers, hashed while streaming, when the client
+// accepts them, else in the headers once the file is hashed.
+pub async fn file_body(
+    path: &Path,
+    size: u64,
+    hash: Option<Vec<u8>>,
+    trailers: bool,
+    headers: &mut HeaderMap,
+) -> std::io::Result<Body> {
+    if hash.is_none() && trailers {
+        let file = File::open(path).await?;
+        // hyper only sends trailers declared here, compared case-sensitively
+        // against the lowercase field names
+        headers.insert(
+            http::header::TRAILER,
+            HeaderValue::from_static("repr-digest, digest"),
+        );
+        return Ok(traced_body(Body::new(HashingBody::new(file, size)), stream_span(size)));
+    }
+    let hash = match hash {
+        Some(hash) => hash,
+        None => file_sha256(path).await?,
+    };
+    let file = File::open(path).await?;
+    insert_digest(headers, &hash);
+    insert_etag(headers, &hash);
+    headers.insert(http::header::CONTENT_LENGTH, HeaderValue::from(size));
+    Ok(traced_body(Body::from_stream(ReaderStream::new(file)), stream_span(size)))
+}
+
+fn stream_span(size: u64) -> tracing::Span {
+    tracing::info_span!("artifact.stream", bytes = size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    async fn collect(body: Body) -> (Vec<u8>, Option<HeaderMap>) {
+        let mut body = std::pin::pin!(body);
+        let mut data = Vec::new();
+        let mut trailers = None;
+        while let Some(frame) = std::future::poll_fn(|cx| body.as_mut().poll_frame(cx)).await {
+            match frame.unwrap().into_data() {
+                Ok(chunk) => data.extend_from_slice(&chunk),
+                Err(frame) => trailers = frame.into_trailers().ok(),
+            }
+        }
+        (data, trailers)
+    }
+
+    fn archive() -> (tempfile::NamedTempFile, Vec<u8>) {
+        let content: Vec<u8> = (0..200_000u32).flat_map(|n| n.to_le_bytes()).collect();
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(&content).unwrap();
+        (file, content)
+    }
+
+    fn expected(content: &[u8]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        insert_digest(&mut headers, &sha256(content));
+        headers
+    }
+
+    #[tokio::test]
+    async fn trailers_carry_the_digest_of_the_body() {
+        let (file, content) = archive();
+        let mut headers = HeaderMap::new();
+        let body = file_body(file.path(), content.len() as u64, None, true, &mut headers).await.unwrap();
+        assert_eq!(headers[http::header::TRAILER], "repr-digest, digest");
+        assert!(headers.get(http::header::CONTENT_LENGTH).is_none());
+        assert!(headers.get(REPR_DIGEST).is_none());
+
+        let (data, trailers) = collect(body).await;
+        assert_eq!(data, content);
+        let trailers = trailers.unwrap();
+        let expected = expected(&content);
+        assert_eq!(trailers[REPR_DIGEST], expected[REPR_DIGEST]);
+        assert_eq!(trailers[DIGEST], expected[DIGEST]);
+    }
+
+    #[tokio::test]
+    async fn headers_carry_the_digest_and_length_without_trailers() {
+        let (file, content) = archive();
+        for hash in [None, Some(sha256(&content))] {
+            let mut headers = HeaderMap::new();
+            let body = file_body(file.path(), content.len() as u64, hash, false, &mut headers)
+                .await
+                .unwrap();
+            let (data, trailers) = collect(body).await;
+            assert_eq!(data, content);
+            assert!(trailers.is_none());
+            assert_eq!(headers[http::header::CONTENT_LENGTH], content.len().to_string().as_str());
+            let expected = expected(&content);
+            assert_eq!(headers[REPR_DIGEST], expected[REPR_DIGEST]);
+            assert_eq!(headers[DIGEST], expected[DIGEST]);
+            let etag = format!("\"sha256:{}\"", hex::encode(sha256(&content)));
+            assert_eq!(headers[http::header::ETAG], etag.as_str());
+            assert!(headers.get(http::header::TRAILER).is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn known_digest_goes_in_the_headers_even_with_trailers() {
+        let (file, content) = archive();
+        let mut headers = HeaderMap::new();
+        let body = file_body(file.path(), content.len() as u64, Some(sha256(&content)), true, &mut headers)
+            .await
+            .unwrap();
+        assert!(headers.get(http::header::TRAILER).is_none());
+        assert_eq!(headers[REPR_DIGEST], expected(&content)[REPR_DIGEST]);
+        assert_eq!(collect(body).await, (content, None));
+    }
+
+    #[tokio::test]
+    async fn tail_hashes_a_file_while_it_is_written() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("images-1.tar");
+        let mut tail = Tail::start(&path);
+
+        let mut content = Vec::new();
+        tokio::time::sleep(TAIL_POLL * 2).await;
+        let mut file = std::fs::File::create(&path).unwrap();
+        for n in 0..5u8 {
+            let chunk = vec![n; 100_000];
+            file.write_all(&chunk).unwrap();
+            content.extend_from_slice(&chunk);
+            tokio::time::sleep(TAIL_POLL).await;
+        }
+        drop(file);
+        assert_eq!(tail.finish().await, Some(sha256(&content)));
+    }
+
+    #[tokio::test]
+    async fn tail_gives_up_on_a_replaced_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("images-1.tar");
+        std::fs::write(&path, b"partial").unwrap();
+        let mut tail = Tail::start(&path);
+        tokio::time::sleep(TAIL_POLL * 2).await;
+
+        let replacement = dir.path().join("images-1.tar.tmp");
+        std::fs::write(&replacement, b"partial").unwrap();
+        std::fs::rename(&replacement, &path).unwrap();
+        assert_eq!(tail.finish().await, None);
+    }
+
+    #[test]
+    fn sidecar_is_in_sha256sum_format() {
+        let hash = sha256(b"chart");
+        let (headers, body) = sidecar(&hash, "nginx-15.4.0.tgz");
+        assert_eq!(body, format!("{}  nginx-15.4.0.tgz\n", hex::encode(&hash)));
+        assert_eq!(headers[http::header::CONTENT_DISPOSITION], "attachment; filename=\"nginx-15.4.0.tgz.sha256\"");
+        assert_eq!(parse_sha256(&format!(" sha256:{} ", hex::encode(&hash))), Some(hash));
+        assert_eq!(parse_sha256("sha256:abcd"), None);
+        assert_eq!(parse_sha256("md5:abcd"), None);
+    }
+}
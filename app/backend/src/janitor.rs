@@ -0,0 +1,305 @@
+use serde::Serialize;
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Instant, SystemTime},
+};
+#[cfg(unix)]
+use std::os::unix::fs::DirBuilderExt;
+use tracing::{debug, error, info, warn};
+use utoipa::ToSchema;
+
+use crate::{config::LiveConfig, shutdown::Shutdown};
+
+// Subdirectory of the system temp directory holding every temp artifact of
+// this server, the only directory the janitor sweeps
+const TEMP_SUBDIR: &str = "tessark";
+
+// Temp entries created by the handlers: image archives and digest files,
+// chart directories, helm registry configs and skopeo auth files
+const TEMP_PREFIXES: [&str; 4] = ["images-", "charts-", "helm-registry-", "auth-"];
+
+// Created on first use, private to the backend user
+pub fn temp_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(TEMP_SUBDIR);
+    let mut builder = std::fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    builder.mode(0o700);
+    if let Err(e) = builder.create(&dir) {
+        warn!("Failed to create temp directory {}: {}", dir.display(), e);
+    }
+    dir
+}
+
+// Removes temp artifacts left behind by crashes, restarts or failed cleanups:
+// once at startup, then periodically
+pub struct Janitor {
+    dir: PathBuf,
+    // `temp.maxAge` and `temp.sweepInterval`
+    config: Arc<LiveConfig>,
+    // Knows which artifacts are still held by a request
+    shutdown: Arc<Shutdown>,
+    stats: Mutex<Stats>,
+}
+
+#[derive(Clone, Default, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Stats {
+    pub sweeps: u64,
+    pub last_sweep: Option<String>,
+    pub last_sweep_duration_ms: Option<u64>,
+    pub last_sweep_removed: u64,
+    pub removed_entries: u64,
+    pub removed_bytes: u64,
+    pub failures: u64,
+}
+
+#[derive(Default, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Usage {
+    pub entries: u64,
+    pub bytes: u64,
+    pub in_use: u64,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+#[schema(as = JanitorReport)]
+pub struct Report {
+    pub directory: String,
+    pub max_age_seconds: u64,
+    pub interval_seconds: u64,
+    #[serde(flatten)]
+    pub stats: Stats,
+    pub current: Usage,
+}
+
+impl Janitor {
+    pub fn new(shutdown: Arc<Shutdown>, config: Arc<LiveConfig>) -> Self {
+        let dir = temp_dir();
+        let temp = config.current().temp.clone();
+        info!(
+            "Temp janitor: {} (max age {:?}, every {:?})",
+            dir.display(),
+            temp.max_age,
+            temp.sweep_interval
+        );
+        Self {
+            dir,
+            config,
+            shutdown,
+            stats: Mutex::new(Stats::default()),
+        }
+    }
+
+    // Sweep every interval, after the startup sweep
+    pub async fn run(self: Arc<Self>) {
+        let interval = self.config.current().temp.sweep_interval;
+        let start = tokio::time::Instant::now() + interval;
+        let mut ticker = tokio::time::interval_at(start, interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            let janitor = self.clone();
+            if let Err(e) = tokio::task::spawn_blocking(move || janitor.sweep()).await {
+                error!("Temp janitor sweep failed: {}", e);
+            }
+        }
+    }
+
+    // Remove prefixed entries older than the max age that no request holds
+    pub fn sweep(&self) {
+        let start = Instant::now();
+        let max_age = self.config.current().temp.max_age;
+        let mut removed = 0;
+        let mut removed_bytes = 0;
+        let mut failures = 0;
+        for path in temp_entries(&self.dir) {
+            if self.shutdown.is_tracked(&path) {
+                continue;
+            }
+            let Ok(meta) = std::fs::symlink_metadata(&path) else {
+                continue;
+            };
+            let age = meta
+                .modified()
+                .ok()
+                .and_then(|m| SystemTime::now().duration_since(m).ok())
+                .unwrap_or_default();
+            if age < max_age {
+                continue;
+            }
+            let size = disk_usage(&path);
+            let result = if meta.is_dir() {
+                std::fs::remove_dir_all(&path)
+            } else {
+                std::fs::remove_file(&path)
+            };
+            match result {
+                Ok(()) => {
+                    debug!("Removed stale temp artifact {} ({:?} old)", path.display(), age);
+                    removed += 1;
+                    removed_bytes += size;
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    warn!("Failed to remove stale temp artifact {}: {}", path.display(), e);
+                    failures += 1;
+                }
+            }
+        }
+        if removed > 0 {
+            info!("Temp janitor removed {} stale artifacts ({} bytes)", removed, removed_bytes);
+        }
+
+        let mut stats = self.stats.lock().unwrap();
+        stats.sweeps += 1;
+        stats.last_sweep = Some(humantime::format_rfc3339_seconds(SystemTime::now()).to_string());
+        stats.last_sweep_duration_ms = Some(start.elapsed().as_millis() as u64);
+        stats.last_sweep_removed = removed;
+        stats.removed_entries += removed;
+        stats.removed_bytes += removed_bytes;
+        stats.failures += failures;
+    }
+
+    // Sweep statistics plus what the temp directory currently holds
+    pub fn report(&self) -> Report {
+        let mut current = Usage::default();
+        for path in temp_entries(&self.dir) {
+            current.entries += 1;
+            current.bytes += disk_usage(&path);
+            if self.shutdown.is_tracked(&path) {
+                current.in_use += 1;
+            }
+        }
+        let temp = self.config.current().temp.clone();
+        Report {
+            directory: self.dir.display().to_string(),
+            max_age_seconds: temp.max_age.as_secs(),
+            interval_seconds: temp.sweep_interval.as_secs(),
+            stats: self.stats.lock().unwrap().clone(),
+            current,
+        }
+    }
+}
+
+fn temp_entries(dir: &Path) -> Vec<PathBuf> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    entries
+        .filter_map(Result::ok)
+        .filter(|e| {
+            let name = e.file_name();
+            let name = name.to_string_lossy();
+            TEMP_PREFIXES.iter().any(|p| name.starts_with(p))
+        })
+        .map(|e| e.path())
+        .collect()
+}
+
+// Bytes used by the temp artifacts in `dir`
+pub fn temp_disk_usage(dir: &Path) -> u64 {
+    temp_entries(dir).iter().map(|p| disk_usage(p)).sum()
+}
+
+fn disk_usage(path: &Path) -> u64 {
+    let Ok(meta) = std::fs::symlink_metadata(path) else {
+        return 0;
+    };
+    if !meta.is_dir() {
+        return meta.len();
+    }
+    std::fs::read_dir(path)
+        .map(|entries| entries.filter_map(Result::ok).map(|e| disk_usage(&e.path())).sum())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, Temp};
+    use std::{fs::File, time::Duration};
+
+    fn janitor(dir: &Path, max_age: Duration) -> Janitor {
+        let config = Config {
+            temp: Temp {
+                max_age,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let config = Arc::new(LiveConfig::from_config(None, config).unwrap());
+        Janitor {
+            dir: dir.to_path_buf(),
+            shutdown: Arc::new(Shutdown::new(config.clone())),
+            config,
+            stats: Mutex::new(Stats::default()),
+        }
+    }
+
+    fn create(path: &Path, age: Duration) {
+        if path.extension().is_some() {
+            std::fs::write(path, b"data").unwrap();
+        } else {
+            std::fs::create_dir(path).unwrap();
+            std::fs::write(path.join("Chart.yaml"), b"name: demo").unwrap();
+        }
+        File::open(path).unwrap().set_modified(SystemTime::now() - age).unwrap();
+    }
+
+    #[test]
+    fn sweep_removes_prefixed_entries_older_than_max_age() {
+        let dir = tempfile::tempdir().unwrap();
+        let old = Duration::from_secs(2 * 60 * 60);
+        let fresh = Duration::from_secs(60);
+        for name in ["images-1.tar", "charts-1", "helm-registry-1", "auth-1.json"] {
+            create(&dir.path().join(name), old);
+        }
+        create(&dir.path().join("images-2.tar"), fresh);
+        create(&dir.path().join("charts-2"), fresh);
+        create(&dir.path().join("tessark-registries-1.conf"), old);
+        create(&dir.path().join("other.tar"), old);
+
+        let janitor = janitor(dir.path(), Duration::from_secs(60 * 60));
+        janitor.sweep();
+
+        let mut left: Vec<String> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        left.sort();
+        assert_eq!(left, ["charts-2", "images-2.tar", "other.tar", "tessark-registries-1.conf"]);
+        let stats = janitor.report().stats;
+        assert_eq!((stats.sweeps, stats.last_sweep_removed, stats.failures), (1, 4, 0));
+        assert_eq!(stats.removed_bytes, 2 * 4 + 2 * 10);
+    }
+
+    #[test]
+    fn sweep_skips_artifacts_held_by_a_request() {
+        let dir = tempfile::tempdir().unwrap();
+        let old = Duration::from_secs(2 * 60 * 60);
+        let held = dir.path().join("charts-held");
+        create(&held, old);
+        create(&dir.path().join("charts-stale"), old);
+
+        let janitor = janitor(dir.path(), Duration::from_secs(60 * 60));
+        let guard = janitor.shutdown.track(&held);
+        janitor.sweep();
+        assert!(held.exists());
+        assert!(!dir.path().join("charts-stale").exists());
+        let report = janitor.report();
+        assert_eq!((report.current.entries, report.current.in_use), (1, 1));
+
+        drop(guard);
+        assert!(!held.exists());
+    }
+
+    #[test]
+    fn temp_artifacts_live_in_a_dedicated_subdirectory() {
+        let dir = temp_dir();
+        assert_eq!(dir, std::env::temp_dir().join("tessark"));
+        assert!(dir.is_dir());
+    }
+}
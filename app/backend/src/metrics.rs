@@ -0,0 +1,324 @@
+use axum::{
+    body::{Body, Bytes, HttpBody},
+    extract::{MatchedPath, Request},
+    http::{header, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use hyper::body::{Frame, SizeHint};
+use once_cell::sync::Lazy;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use std::{
+    collections::HashSet,
+    pin::Pin,
+    sync::RwLock,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tracing::error;
+
+use crate::janitor;
+
+static REGISTRY: Lazy<Registry> = Lazy::new(Registry::new);
+
+fn register<T: prometheus::core::Collector + Clone + 'static>(metric: T) -> T {
+    // Names are constants, registration only fails on duplicates
+    REGISTRY.register(Box::new(metric.clone())).unwrap();
+    metric
+}
+
+static HTTP_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new("tessark_http_requests_total", "HTTP requests by route, method and status"),
+            &["route", "method", "status"],
+        )
+        .unwrap(),
+    )
+});
+
+static HTTP_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register(
+        HistogramVec::new(
+            HistogramOpts::new(
+                "tessark_http_request_duration_seconds",
+                "Time to produce the response headers, by route, method and status",
+            ),
+            &["route", "method", "status"],
+        )
+        .unwrap(),
+    )
+});
+
+static PULL_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register(
+        HistogramVec::new(
+            HistogramOpts::new(
+                "tessark_pull_duration_seconds",
+                "Time to fetch an artifact before streaming it, by artifact type and outcome",
+            )
+            .buckets(vec![0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0]),
+            &["artifact", "outcome"],
+        )
+        .unwrap(),
+    )
+});
+
+static BYTES_SERVED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new("tessark_bytes_served_total", "Artifact bytes served, by artifact type"),
+            &["artifact"],
+        )
+        .unwrap(),
+    )
+});
+
+static PULLS_IN_FLIGHT: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register(
+        IntGaugeVec::new(
+            Opts::new("tessark_pulls_in_flight", "Pulls being fetched, by artifact type"),
+            &["artifact"],
+        )
+        .unwrap(),
+    )
+});
+
+static TOOL_FAILURES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new(
+                "tessark_tool_failures_total",
+                "Failed skopeo and helm runs, by tool and class (spawn, timeout, verification or the stderr class)",
+            ),
+            &["tool", "class"],
+        )
+        .unwrap(),
+    )
+});
+
+static UPSTREAM_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register(
+        HistogramVec::new(
+            HistogramOpts::new(
+                "tessark_upstream_request_duration_seconds",
+                "Latency of registry and chart repository requests, by configured registry host or \"other\"",
+            ),
+            &["host"],
+        )
+        .unwrap(),
+    )
+});
+
+static TEMP_DISK: Lazy<IntGauge> = Lazy::new(|| {
+    register(IntGauge::new("tessark_temp_disk_bytes", "Disk used by temporary artifacts").unwrap())
+});
+
+// Register every metric so series are exported before their first sample
+pub fn init() {
+    Lazy::force(&HTTP_REQUESTS);
+    Lazy::force(&HTTP_DURATION);
+    Lazy::force(&PULL_DURATION);
+    Lazy::force(&BYTES_SERVED);
+    Lazy::force(&PULLS_IN_FLIGHT);
+    Lazy::force(&TOOL_FAILURES);
+    Lazy::force(&UPSTREAM_DURATION);
+    Lazy::force(&TEMP_DISK);
+}
+
+// Request counts and latencies, labelled by route template rather than raw path
+pub async fn track(request: Request, next: Next) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or_else(|| "unmatched".to_string(), |p| p.as_str().to_string());
+    let method = request.method().to_string();
+    let start = Instant::now();
+    let response = next.run(request).await;
+    let status = response.status();
+    let labels = [route.as_str(), method.as_str(), status.as_str()];
+    HTTP_REQUESTS.with_label_values(&labels).inc();
+    HTTP_DURATION.with_label_values(&labels).observe(start.elapsed().as_secs_f64());
+    response
+}
+
+// Held while an artifact is being fetched
+pub struct InFlight(&'static str);
+
+impl InFlight {
+    pub fn new(artifact: &'static str) -> Self {
+        PULLS_IN_FLIGHT.with_label_values(&[artifact]).inc();
+        Self(artifact)
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        PULLS_IN_FLIGHT.with_label_values(&[self.0]).dec();
+    }
+}
+
+// Pull duration, and bytes served as the client actually receives them:
+// aborted downloads only count what was sent
+pub fn observe_pull(artifact: &str, start: Instant, response: Response) -> Response {
+    let outcome = if response.status().is_success() { "success" } else { "failure" };
+    PULL_DURATION
+        .with_label_values(&[artifact, outcome])
+        .observe(start.elapsed().as_secs_f64());
+    if !response.status().is_success() {
+        return response;
+    }
+    let served = BYTES_SERVED.with_label_values(&[artifact]);
+    response.map(|inner| Body::new(CountedBody { inner, served }))
+}
+
+struct CountedBody {
+    inner: Body,
+    served: IntCounter,
+}
+
+impl HttpBody for CountedBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = &mut *self;
+        let poll = Pin::new(&mut this.inner).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &poll {
+            if let Some(data) = frame.data_ref() {
+                this.served.inc_by(data.len() as u64);
+            }
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+pub fn tool_failure(tool: &str, class: &str) {
+    TOOL_FAILURES.with_label_values(&[tool, class]).inc();
+}
+
+// Hosts of the configured registries and mirrors; any other host is labelled
+// "other" to keep the series bounded
+static UPSTREAM_HOSTS: Lazy<RwLock<HashSet<String>>> = Lazy::new(Default::default);
+
+pub fn set_upstream_hosts<'a>(hosts: impl IntoIterator<Item = &'a str>) {
+    *UPSTREAM_HOSTS.write().unwrap() = hosts.into_iter().map(crate::registry::normalize_host).collect();
+}
+
+fn upstream_label(url: &str) -> String {
+    let host = crate::registry::normalize_host(url);
+    if UPSTREAM_HOSTS.read().unwrap().contains(&host) {
+        host
+    } else {
+        "other".to_string()
+    }
+}
+
+pub fn observe_upstream(url: &str, elapsed: Duration) {
+    UPSTREAM_DURATION
+        .with_label_values(&[&upstream_label(url)])
+        .observe(elapsed.as_secs_f64());
+}
+
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    tag = "probes",
+    security(()),
+    responses((status = 200, description = "Prometheus text exposition", body = String, content_type = "text/plain"))
+)]
+pub async fn render() -> Response {
+    let temp_bytes = tokio::task::spawn_blocking(|| janitor::temp_disk_usage(&janitor::temp_dir()))
+        .await
+        .unwrap_or_default();
+    TEMP_DISK.set(temp_bytes as i64);
+
+    let mut buf = Vec::new();
+    if let Err(e) = TextEncoder::new().encode(&REGISTRY.gather(), &mut buf) {
+        error!("Failed to encode metrics: {}", e);
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+    (
+        [(header::CONTENT_TYPE, HeaderValue::from_static("text/plain; version=0.0.4"))],
+        buf,
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn upstream_label_is_bounded_to_configured_hosts() {
+        set_upstream_hosts(["docker.io", "localhost:5000", "https://GHCR.io/"]);
+        assert_eq!(upstream_label("https://registry-1.docker.io/v2/library/nginx/tags/list"), "docker.io");
+        assert_eq!(upstream_label("http://localhost:5000/v2/"), "localhost:5000");
+        assert_eq!(upstream_label("https://ghcr.io/token?scope=x"), "ghcr.io");
+        assert_eq!(upstream_label("https://charts.example.com/index.yaml"), "other");
+        assert_eq!(upstream_label("http://localhost:5001/v2/"), "other");
+    }
+
+    fn served(artifact: &str) -> u64 {
+        BYTES_SERVED.with_label_values(&[artifact]).get()
+    }
+
+    async fn frame(mut body: Pin<&mut Body>) -> Option<Frame<Bytes>> {
+        std::future::poll_fn(|cx| body.as_mut().poll_frame(cx)).await.map(Result::unwrap)
+    }
+
+    #[tokio::test]
+    async fn counts_bytes_as_they_are_sent() {
+        let chunks = tokio_util::io::ReaderStream::with_capacity(&b"first chunk, then more"[..], 11);
+        let response = ([(header::CONTENT_LENGTH, "22")], Body::from_stream(chunks)).into_response();
+        let response = observe_pull("test-aborted", Instant::now(), response);
+        assert_eq!(served("test-aborted"), 0);
+
+        // A client gone after the first chunk
+        let mut body = Box::pin(response.into_body());
+        frame(body.as_mut()).await.unwrap();
+        drop(body);
+        assert_eq!(served("test-aborted"), 11);
+
+        let failed = observe_pull("test-failed", Instant::now(), (StatusCode::NOT_FOUND, "missing").into_response());
+        let mut body = Box::pin(failed.into_body());
+        while frame(body.as_mut()).await.is_some() {}
+        assert_eq!(served("test-failed"), 0);
+    }
+
+    #[tokio::test]
+    async fn counts_chunked_responses_with_trailers() {
+        let content: Vec<u8> = (0..100_000u32).flat_map(|n| n.to_le_bytes()).collect();
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut file, &content).unwrap();
+        let mut headers = axum::http::HeaderMap::new();
+        let body = crate::integrity::file_body(file.path(), content.len() as u64, None, true, &mut headers)
+            .await
+            .unwrap();
+        assert!(!headers.contains_key(header::CONTENT_LENGTH));
+
+        let response = observe_pull("test-trailers", Instant::now(), (headers, body).into_response());
+        let mut body = Box::pin(response.into_body());
+        let mut trailers = None;
+        while let Some(frame) = frame(body.as_mut()).await {
+            if frame.is_trailers() {
+                trailers = frame.into_trailers().ok();
+            }
+        }
+        assert!(trailers.is_some());
+        assert_eq!(served("test-trailers"), content.len() as u64);
+    }
+}
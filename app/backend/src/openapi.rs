@@ -0,0 +1,201 @@
+use axum::Router;
+use utoipa::{
+    openapi::{
+        path::{Operation, PathItem},
+        security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme},
+        ContentBuilder, Ref, ResponseBuilder,
+    },
+    Modify, OpenApi,
+};
+use utoipa_swagger_ui::SwaggerUi;
+
+use crate::{audit, cosign, janitor, metrics, readiness, vault};
+
+// OpenAPI 3.1 document of the HTTP API, built from the route handlers' path
+// annotations and the request and response types
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "Tessark API",
+        description = "Pull container images and Helm charts as archives, browse registries and chart repositories."
+    ),
+    paths(
+        crate::fetch_index,
+        crate::pull_image,
+        crate::pull_image_post,
+        crate::pull_chart,
+        crate::pull_chart_post,
+        crate::relocate_chart,
+        crate::relocate_chart_post,
+        crate::registry_list,
+        crate::registry_tags,
+        crate::chart_versions,
+        crate::chart_versions_post,
+        crate::verify_image,
+        crate::verify_image_post,
+        crate::list_credentials,
+        crate::create_credential,
+        crate::get_credential,
+        crate::update_credential,
+        crate::delete_credential,
+        crate::query_audit,
+        crate::janitor_stats,
+        crate::readiness_report,
+        crate::health_check,
+        crate::readiness_check,
+        metrics::render,
+    ),
+    components(schemas(
+        crate::error::Body,
+        crate::PullParams,
+        crate::PullChartParams,
+        crate::RelocateChartParams,
+        crate::VerifyParams,
+        crate::RegistryListResponse,
+        crate::RegistryTagsResponse,
+        crate::ChartVersionsParams,
+        crate::ChartVersionsResponse,
+        crate::ResolvedChartVersion,
+        crate::CredentialList,
+        crate::AuditEvents,
+        audit::AuditEvent,
+        cosign::Verification,
+        vault::CredentialSummary,
+        vault::CredentialInput,
+        janitor::Report,
+        readiness::Report,
+    )),
+    modifiers(&Security, &Errors),
+    security(("apiKey" = []), ("bearer" = [])),
+    tags(
+        (name = "images", description = "Container images"),
+        (name = "charts", description = "Helm charts from OCI registries and chart repositories"),
+        (name = "registry", description = "Registry catalog and tags"),
+        (
+            name = "admin",
+            description = "Credential store, audit log, temp janitor and readiness report; requires the admin role"
+        ),
+        (name = "probes", description = "Health, readiness and metrics; no authentication"),
+    )
+)]
+pub struct ApiDoc;
+
+// `X-API-Key` or `Authorization: Bearer` with an API key or OIDC token
+struct Security;
+
+impl Modify for Security {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme("apiKey", SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new("X-API-Key"))));
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+        );
+    }
+}
+
+// Every /api operation answers errors with the JSON body of `ApiError`
+struct Errors;
+
+impl Modify for Errors {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let response = |description: &str| {
+            ResponseBuilder::new()
+                .description(description)
+                .content(
+                    "application/json",
+                    ContentBuilder::new().schema(Some(Ref::from_schema_name("Error"))).build(),
+                )
+                .build()
+        };
+        for (path, item) in openapi.paths.paths.iter_mut() {
+            if !path.starts_with("/api/") {
+                continue;
+            }
+            for (_, operation) in operations_mut(item) {
+                let responses = &mut operation.responses.responses;
+                responses.insert("4XX".to_string(), response("Client error, see `code`").into());
+                responses.insert("5XX".to_string(), response("Server or upstream error, see `code`").into());
+            }
+        }
+    }
+}
+
+fn operations_mut(item: &mut PathItem) -> impl Iterator<Item = (&'static str, &mut Operation)> {
+    [
+        ("get", item.get.as_mut()),
+        ("put", item.put.as_mut()),
+        ("post", item.post.as_mut()),
+        ("delete", item.delete.as_mut()),
+        ("patch", item.patch.as_mut()),
+    ]
+    .into_iter()
+    .filter_map(|(method, operation)| Some((method, operation?)))
+}
+
+pub fn document() -> utoipa::openapi::OpenApi {
+    let mut openapi = ApiDoc::openapi();
+    // Filled from Cargo.toml, which has no license
+    openapi.info.license = None;
+    openapi
+}
+
+// The document at /api/openapi.json and Swagger UI, with its assets bundled
+// in the binary, at /api/docs
+pub fn routes<S: Clone + Send + Sync + 'static>() -> Router<S> {
+    SwaggerUi::new("/api/docs").url("/api/openapi.json", document()).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    // (path, method) -> handler, from the table the router is built from
+    fn router_operations() -> BTreeMap<(String, String), String> {
+        crate::route_table()
+            .into_iter()
+            .map(|(path, method, handler)| {
+                let path = path
+                    .split('/')
+                    .map(|s| s.strip_prefix(':').map_or_else(|| s.to_string(), |p| format!("{{{}}}", p)))
+                    .collect::<Vec<_>>()
+                    .join("/");
+                ((path, method.to_string()), handler.to_string())
+            })
+            .collect()
+    }
+
+    fn spec_operations() -> BTreeMap<(String, String), String> {
+        let mut openapi = document();
+        let mut operations = BTreeMap::new();
+        for (path, item) in openapi.paths.paths.iter_mut() {
+            for (method, operation) in operations_mut(item) {
+                let id = operation.operation_id.clone().unwrap_or_default();
+                operations.insert((path.clone(), method.to_string()), id);
+            }
+        }
+        operations
+    }
+
+    #[test]
+    fn spec_matches_routes() {
+        let routes = router_operations();
+        let spec = spec_operations();
+        for (op, handler) in &routes {
+            assert_eq!(spec.get(op), Some(handler), "route {:?} is not documented", op);
+        }
+        for op in spec.keys() {
+            assert!(routes.contains_key(op), "documented operation {:?} has no route", op);
+        }
+    }
+
+    #[test]
+    fn spec_is_openapi_3_1() {
+        let json = serde_json::to_value(document()).unwrap();
+        assert!(json["openapi"].as_str().unwrap().starts_with("3.1"));
+        for schema in ["Error", "PullParams", "PullChartParams", "RegistryListResponse", "RegistryTagsResponse"] {
+            assert!(json["components"]["schemas"][schema].is_object(), "missing schema {}", schema);
+        }
+    }
+}
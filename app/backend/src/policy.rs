@@ -0,0 +1,481 @@
+use serde::Deserialize;
+use std::fmt;
+use tracing::info;
+
+use crate::{auth::Identity, registry::Reference};
+
+#[derive(Deserialize, Default)]
+#[serde(default, rename_all = "camelCase")]
+struct Patterns {
+    allow: Vec<String>,
+    deny: Vec<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, rename_all = "camelCase")]
+struct TagRules {
+    // Tags that may never be pulled, e.g. `latest`
+    deny: Vec<String>,
+    // Only digests and full semver tags (1.2.3) are accepted
+    deny_mutable: bool,
+}
+
+// One entry of the policy file. A policy applies to callers matching `subjects`
+// or `roles`; when both are empty it applies to everybody.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Policy {
+    name: String,
+    #[serde(default)]
+    subjects: Vec<String>,
+    #[serde(default)]
+    roles: Vec<String>,
+    #[serde(default)]
+    registries: Patterns,
+    // Matched against `registry/repository`, e.g. `ghcr.io/org/*`
+    #[serde(default)]
+    repositories: Patterns,
+    #[serde(default)]
+    tags: TagRules,
+    // Repositories that must be pulled by digest
+    #[serde(default)]
+    require_digest: Vec<String>,
+    // Compressed image size from the manifest, e.g. `2GiB` or a byte count
+    #[serde(default)]
+    max_image_size: Option<Size>,
+    // Chart names (`ingress-nginx`) or full chart repositories
+    #[serde(default)]
+    blocked_charts: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct PolicyFile {
+    policies: Vec<Policy>,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(try_from = "serde_yaml::Value")]
+struct Size(u64);
+
+impl TryFrom<serde_yaml::Value> for Size {
+    type Error = String;
+
+    fn try_from(value: serde_yaml::Value) -> Result<Self, Self::Error> {
+        match value {
+            serde_yaml::Value::Number(n) => n.as_u64().map(Size).ok_or_else(|| format!("invalid size: {}", n)),
+            serde_yaml::Value::String(s) => parse_size(&s).map(Size).ok_or_else(|| format!("invalid size: {}", s)),
+            other => Err(format!("invalid size: {:?}", other)),
+        }
+    }
+}
+
+pub fn parse_size(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: f64 = number.parse().ok()?;
+    let factor: u64 = match unit.trim().to_lowercase().as_str() {
+        "" | "b" => 1,
+        "kb" => 1_000,
+        "mb" => 1_000_000,
+        "gb" => 1_000_000_000,
+        "kib" | "k" => 1 << 10,
+        "mib" | "m" => 1 << 20,
+        "gib" | "g" => 1 << 30,
+        _ => return None,
+    };
+    Some((number * factor as f64) as u64)
+}
+
+// A denied request: the policy and rule that matched
+#[derive(Debug)]
+pub struct Violation {
+    pub policy: String,
+    pub rule: &'static str,
+    pub detail: String,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "policy '{}' rule {}: {}", self.policy, self.rule, self.detail)
+    }
+}
+
+pub enum Artifact {
+    Image,
+    Chart,
+}
+
+pub struct PolicyEngine {
+    policies: Vec<Policy>,
+}
+
+impl PolicyEngine {
+    // PULL_POLICY_FILE: YAML file with a `policies` list
+    pub async fn from_env() -> anyhow::Result<Option<Self>> {
+        let Some(path) = std::env::var("PULL_POLICY_FILE").ok().filter(|p| !p.is_empty()) else {
+            return Ok(None);
+        };
+        let text = tokio::fs::read_to_string(&path)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to read PULL_POLICY_FILE {}: {}", path, e))?;
+        let file: PolicyFile = serde_yaml::from_str(&text)
+            .map_err(|e| anyhow::anyhow!("Invalid PULL_POLICY_FILE {}: {}", path, e))?;
+        info!("Loaded {} pull policies from {}", file.policies.len(), path);
+        Ok(Some(Self {
+            policies: file.policies,
+        }))
+    }
+
+    fn applicable<'a>(&'a self, identity: &'a Option<Identity>) -> impl Iterator<Item = &'a Policy> {
+        let subject = identity.as_ref().map_or("anonymous", |i| i.subject.as_str());
+        self.policies.iter().filter(move |p| {
+            if p.subjects.is_empty() && p.roles.is_empty() {
+                return true;
+            }
+            p.subjects.iter().any(|s| glob_match(s, subject))
+                || identity
+                    .as_ref()
+                    .is_some_and(|i| p.roles.iter().any(|r| i.has_role(r)))
+        })
+    }
+
+    // Registry, repository and chart name rules, checked before contacting upstream
+    pub fn check_source(
+        &self,
+        identity: &Option<Identity>,
+        reference: &Reference,
+        artifact: Artifact,
+    ) -> Result<(), Violation> {
+        let reference = reference.normalized();
+        let name = reference.name();
+        for policy in self.applicable(identity) {
+            let violation = |rule, detail: String| Violation {
+                policy: policy.name.clone(),
+                rule,
+                detail,
+            };
+            if !policy.registries.allows(&reference.registry) {
+                return Err(violation("registries", format!("registry {} is not allowed", reference.registry)));
+            }
+            if !policy.repositories.allows(&name) {
+                return Err(violation("repositories", format!("repository {} is not allowed", name)));
+            }
+            if let Artifact::Chart = artifact {
+                let chart = reference.repository.rsplit('/').next().unwrap_or_default();
+                if policy
+                    .blocked_charts
+                    .iter()
+                    .any(|p| glob_match(p, chart) || glob_match(p, &name))
+                {
+                    return Err(violation("blockedCharts", format!("chart {} is blocked", chart)));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Tag and digest pinning rules, checked once versions are resolved. A
+    // reference without tag nor digest stands for `latest`.
+    pub fn check_tag(&self, identity: &Option<Identity>, reference: &Reference) -> Result<(), Violation> {
+        let name = reference.normalized().name();
+        let tag = reference.tag.as_deref().unwrap_or("latest");
+        let pinned = reference.digest.is_some();
+        for policy in self.applicable(identity) {
+            let violation = |rule, detail: String| Violation {
+                policy: policy.name.clone(),
+                rule,
+                detail,
+            };
+            if !pinned && policy.require_digest.iter().any(|p| glob_match(p, &name)) {
+                return Err(violation("requireDigest", format!("{} must be pulled by digest", name)));
+            }
+            if pinned {
+                continue;
+            }
+            if policy.tags.deny.iter().any(|p| glob_match(p, tag)) {
+                return Err(violation("tags.deny", format!("tag {} is not allowed", tag)));
+            }
+            if policy.tags.deny_mutable && !is_immutable_tag(tag) {
+                return Err(violation(
+                    "tags.denyMutable",
+                    format!("tag {} is mutable, use a full version or a digest", tag),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // Smallest size limit among the applicable policies, with the policy name
+    pub fn max_image_size<'a>(&'a self, identity: &'a Option<Identity>) -> Option<(u64, &'a str)> {
+        self.applicable(identity)
+            .filter_map(|p| p.max_image_size.map(|Size(max)| (max, p.name.as_str())))
+            .min_by_key(|(max, _)| *max)
+    }
+}
+
+impl Patterns {
+    fn allows(&self, value: &str) -> bool {
+        if self.deny.iter().any(|p| glob_match(p, value)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|p| glob_match(p, value))
+    }
+}
+
+// Full semver versions are treated as immutable, anything else (latest, 1.2,
+// stable, main) may move.
+fn is_immutable_tag(tag: &str) -> bool {
+    semver::Version::parse(tag.trim_start_matches('v').replace('_', "+").as_str()).is_ok()
+}
+
+// `*` matches any run of characters (slashes included), `?` a single one
+pub fn glob_match(pattern: &str, value: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let value: Vec<char> = value.chars().collect();
+    let (mut p, mut v) = (0, 0);
+    let mut backtrack = None;
+    while v < value.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, v));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == value[v] => {
+                p += 1;
+                v += 1;
+            }
+            _ => match backtrack {
+                Some((bp, bv)) => {
+                    p = bp + 1;
+                    v = bv + 1;
+                    backtrack = Some((bp, bv + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::AuthMethod;
+
+    fn engine(yaml: &str) -> PolicyEngine {
+        let file: PolicyFile = serde_yaml::from_str(yaml).unwrap();
+        PolicyEngine {
+            policies: file.policies,
+        }
+    }
+
+    fn identity(subject: &str, roles: &[&str]) -> Option<Identity> {
+        Some(Identity {
+            subject: subject.to_string(),
+            method: AuthMethod::ApiKey,
+            roles: roles.iter().map(|r| r.to_string()).collect(),
+        })
+    }
+
+    #[test]
+    fn glob_matches() {
+        let cases = [
+            ("*", "", true),
+            ("*", "ghcr.io/org/app", true),
+            ("", "", true),
+            ("", "app", false),
+            ("app", "app", true),
+            // Patterns are anchored at both ends
+            ("app", "my-app", false),
+            ("app", "app-2", false),
+            ("ghcr.io/org/*", "ghcr.io/org/app", true),
+            ("ghcr.io/org/*", "ghcr.io/org/team/app", true),
+            ("ghcr.io/org/*", "ghcr.io/other/app", false),
+            ("ghcr.io/**", "ghcr.io/org/team/app", true),
+            ("**/app", "docker.io/library/app", true),
+            ("*.corp.example/*", "registry.corp.example/app", true),
+            ("*.corp.example/*", "corp.example/app", false),
+            ("ingress-*", "ingress-nginx", true),
+            ("*-nginx", "ingress-nginx", true),
+            ("*nginx*", "nginx", true),
+            ("v?.0", "v1.0", true),
+            ("v?.0", "v10.0", false),
+        ];
+        for (pattern, value, expected) in cases {
+            assert_eq!(glob_match(pattern, value), expected, "{:?} ~ {:?}", pattern, value);
+        }
+    }
+
+    #[test]
+    fn parses_sizes() {
+        let cases = [
+            ("1024", Some(1024)),
+            ("512b", Some(512)),
+            ("2kb", Some(2_000)),
+            ("2KiB", Some(2_048)),
+            ("2k", Some(2_048)),
+            ("1.5MB", Some(1_500_000)),
+            ("1MiB", Some(1 << 20)),
+            ("1 GiB", Some(1 << 30)),
+            ("2G", Some(2 << 30)),
+            ("3gb", Some(3_000_000_000)),
+            ("", None),
+            ("GiB", None),
+            ("1TiB", None),
+            ("-1", None),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(parse_size(input), expected, "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn checks_tags() {
+        let engine = engine(
+            r#"
+policies:
+  - name: pinned
+    tags:
+      deny: ["latest", "*-dev"]
+      denyMutable: true
+    requireDigest: ["ghcr.io/prod/*"]
+"#,
+        );
+        let check = |reference: &str| engine.check_tag(&None, &Reference::parse(reference)).map_err(|v| v.rule);
+        let cases = [
+            ("ghcr.io/org/app:1.2.3", Ok(())),
+            ("ghcr.io/org/app:v1.2.3", Ok(())),
+            ("ghcr.io/org/app:1.2.3_build.1", Ok(())),
+            ("ghcr.io/org/app", Err("tags.deny")),
+            ("ghcr.io/org/app:latest", Err("tags.deny")),
+            ("ghcr.io/org/app:1.2.3-dev", Err("tags.deny")),
+            ("ghcr.io/org/app:1.2", Err("tags.denyMutable")),
+            ("ghcr.io/org/app:stable", Err("tags.denyMutable")),
+            ("ghcr.io/org/app:latest@sha256:abc", Ok(())),
+            ("ghcr.io/prod/app:1.2.3", Err("requireDigest")),
+            ("ghcr.io/prod/app@sha256:abc", Ok(())),
+        ];
+        for (reference, expected) in cases {
+            assert_eq!(check(reference), expected, "{}", reference);
+        }
+    }
+
+    #[test]
+    fn blocks_charts() {
+        let engine = engine(
+            r#"
+policies:
+  - name: charts
+    blockedCharts: ["ingress-*", "ghcr.io/untrusted/*"]
+"#,
+        );
+        let check = |reference: &str, artifact| engine.check_source(&None, &Reference::parse(reference), artifact);
+        assert_eq!(check("ghcr.io/charts/ingress-nginx:4.0.0", Artifact::Chart).unwrap_err().rule, "blockedCharts");
+        assert_eq!(check("ghcr.io/untrusted/redis:1.0.0", Artifact::Chart).unwrap_err().rule, "blockedCharts");
+        assert!(check("ghcr.io/charts/redis:1.0.0", Artifact::Chart).is_ok());
+        // Only charts are subject to the list
+        assert!(check("ghcr.io/charts/ingress-nginx:4.0.0", Artifact::Image).is_ok());
+    }
+
+    #[test]
+    fn applies_by_subject_role_and_sources() {
+        let engine = engine(
+            r#"
+policies:
+  - name: ci
+    subjects: ["ci-*"]
+    registries:
+      allow: ["ghcr.io"]
+  - name: devs
+    roles: ["dev"]
+    repositories:
+      deny: ["ghcr.io/secret/*"]
+    maxImageSize: 1GiB
+  - name: everyone
+    maxImageSize: 2GiB
+"#,
+        );
+        let check = |identity: &Option<Identity>, reference: &str| {
+            engine
+                .check_source(identity, &Reference::parse(reference), Artifact::Image)
+                .map_err(|v| v.policy)
+        };
+        let ci = identity("ci-build", &[]);
+        let dev = identity("alice", &["dev"]);
+        assert_eq!(check(&ci, "docker.io/library/nginx"), Err("ci".to_string()));
+        assert!(check(&ci, "ghcr.io/secret/app").is_ok());
+        assert!(check(&dev, "docker.io/library/nginx").is_ok());
+        assert_eq!(check(&dev, "ghcr.io/secret/app"), Err("devs".to_string()));
+        assert!(check(&None, "ghcr.io/secret/app").is_ok());
+
+        assert_eq!(engine.max_image_size(&dev), Some((1 << 30, "devs")));
+        assert_eq!(engine.max_image_size(&None), Some((2 << 30, "everyone")));
+    }
+
+    #[test]
+    fn matches_normalized_registry_hosts() {
+        let policies = engine(
+            r#"
+policies:
+  - name: hub
+    registries:
+      deny: ["docker.io"]
+  - name: internal
+    subjects: ["ci"]
+    registries:
+      allow: ["registry.corp.example"]
+    repositories:
+      deny: ["registry.corp.example/secret/*"]
+"#,
+        );
+        let check = |identity: &Option<Identity>, reference: &str, artifact| {
+            policies
+                .check_source(identity, &Reference::parse(reference), artifact)
+                .map_err(|v| (v.policy, v.rule))
+        };
+        let hub = Err(("hub".to_string(), "registries"));
+        let cases = [
+            ("docker.io/library/nginx:1.25", hub.clone()),
+            ("nginx:1.25", hub.clone()),
+            ("index.docker.io/library/nginx:1.25", hub.clone()),
+            ("registry-1.docker.io/library/nginx:1.25", hub.clone()),
+            ("registry.hub.docker.com/bitnami/nginx:1.25", hub.clone()),
+            ("DOCKER.IO/library/nginx:1.25", hub.clone()),
+            ("Registry-1.Docker.IO/nginx:1.25", hub.clone()),
+            ("https://index.docker.io/nginx:1.25", hub),
+            ("ghcr.io/org/app:1.0.0", Ok(())),
+        ];
+        for (reference, expected) in cases {
+            assert_eq!(check(&None, reference, Artifact::Image), expected, "{}", reference);
+        }
+
+        let ci = identity("ci", &[]);
+        assert!(check(&ci, "REGISTRY.corp.example/team/app:1.0.0", Artifact::Image).is_ok());
+        assert_eq!(
+            check(&ci, "Registry.Corp.Example/secret/app:1.0.0", Artifact::Image),
+            Err(("internal".to_string(), "repositories"))
+        );
+        assert_eq!(
+            check(&ci, "ghcr.io/org/app:1.0.0", Artifact::Image),
+            Err(("internal".to_string(), "registries"))
+        );
+
+        let allow = engine(
+            r#"
+policies:
+  - name: hub-only
+    registries:
+      allow: ["docker.io"]
+    blockedCharts: ["docker.io/library/*"]
+"#,
+        );
+        let check = |reference: &str, artifact| {
+            allow
+                .check_source(&None, &Reference::parse(reference), artifact)
+                .map_err(|v| v.rule)
+        };
+        assert!(check("INDEX.DOCKER.IO/bitnami/nginx:1.25", Artifact::Image).is_ok());
+        assert_eq!(check("registry-1.docker.io/redis:7.2", Artifact::Chart), Err("blockedCharts"));
+    }
+}
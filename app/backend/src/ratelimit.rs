@@ -0,0 +1,419 @@
+use axum::{
+    extract::{ConnectInfo, Request},
+    http::{HeaderMap, HeaderValue},
+    response::{IntoResponse, Response},
+};
+use ipnet::IpNet;
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tower::{Layer, Service};
+use tracing::{info, warn};
+
+use crate::{auth::Identity, error::ApiError};
+
+// Idle buckets are dropped once this many clients are tracked
+const PRUNE_THRESHOLD: usize = 10_000;
+
+// `<requests>/<period>`, e.g. `30/min`: a bucket of `requests` tokens refilled
+// over `period`, optionally with a different burst size.
+#[derive(Clone, Copy, Debug)]
+pub struct Quota {
+    requests: u32,
+    period: Duration,
+    burst: u32,
+}
+
+impl Quota {
+    pub fn parse(spec: &str) -> Option<Self> {
+        let (requests, period) = spec.trim().split_once('/')?;
+        let requests: u32 = requests.trim().parse().ok().filter(|r| *r > 0)?;
+        let period = match period.trim() {
+            "s" | "sec" | "second" => Duration::from_secs(1),
+            "m" | "min" | "minute" => Duration::from_secs(60),
+            "h" | "hour" => Duration::from_secs(3600),
+            _ => return None,
+        };
+        Some(Self {
+            requests,
+            period,
+            burst: requests,
+        })
+    }
+
+    fn refill_per_sec(&self) -> f64 {
+        self.requests as f64 / self.period.as_secs_f64()
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+enum Decision {
+    Allowed { remaining: u32, reset: u64 },
+    Limited { retry_after: u64, reset: u64 },
+}
+
+// Token buckets of one route group, keyed by client
+pub struct Limiter {
+    group: &'static str,
+    quota: Option<Quota>,
+    trusted_proxies: Arc<Vec<IpNet>>,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl Limiter {
+    fn check(&self, quota: &Quota, key: &str) -> Decision {
+        let now = Instant::now();
+        let rate = quota.refill_per_sec();
+        let capacity = quota.burst as f64;
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.len() >= PRUNE_THRESHOLD {
+            buckets.retain(|_, b| b.tokens + now.duration_since(b.updated).as_secs_f64() * rate < capacity);
+        }
+
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        bucket.tokens = (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate).min(capacity);
+        bucket.updated = now;
+
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+        let reset = ((capacity - bucket.tokens) / rate).ceil() as u64;
+        if allowed {
+            Decision::Allowed {
+                remaining: bucket.tokens.floor() as u32,
+                reset,
+            }
+        } else {
+            Decision::Limited {
+                retry_after: ((1.0 - bucket.tokens) / rate).ceil().max(1.0) as u64,
+                reset,
+            }
+        }
+    }
+
+    // The authenticated identity when there is one, the client address otherwise
+    fn client_key(&self, request: &Request) -> String {
+        if let Some(identity) = request.extensions().get::<Identity>() {
+            return format!("id:{}", identity.subject);
+        }
+        let peer = request
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip());
+        match peer {
+            Some(peer) => format!("ip:{}", client_ip(peer, request.headers(), &self.trusted_proxies)),
+            None => "ip:unknown".to_string(),
+        }
+    }
+}
+
+// Walk X-Forwarded-For from the right while hops are trusted proxies; the first
+// untrusted address is the client. Headers from untrusted peers are ignored.
+fn client_ip(peer: IpAddr, headers: &HeaderMap, trusted: &[IpNet]) -> IpAddr {
+    let is_trusted = |ip: &IpAddr| trusted.iter().any(|net| net.contains(ip));
+    if !is_trusted(&peer) {
+        return peer;
+    }
+    let mut client = peer;
+    let hops = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .collect::<Vec<_>>();
+    for hop in hops.into_iter().rev() {
+        let Ok(ip) = IpAddr::from_str(hop) else {
+            break;
+        };
+        client = ip;
+        if !is_trusted(&ip) {
+            break;
+        }
+    }
+    client
+}
+
+// Route group quotas (`limits.rateLimits`, or RATE_LIMIT_PULLS, RATE_LIMIT_LISTINGS
+// and RATE_LIMIT_INDEX) and the proxies trusted for X-Forwarded-For.
+pub struct RateLimits {
+    trusted_proxies: Arc<Vec<IpNet>>,
+}
+
+impl RateLimits {
+    pub fn from_config(limits: &crate::config::Limits) -> anyhow::Result<Self> {
+        let trusted = limits
+            .trusted_proxies
+            .iter()
+            .map(|entry| {
+                parse_trusted_proxy(entry)
+                    .ok_or_else(|| anyhow::anyhow!("Invalid trusted proxy: {}", entry))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(Self {
+            trusted_proxies: Arc::new(trusted),
+        })
+    }
+
+    pub fn layer(
+        &self,
+        group: &'static str,
+        limit: Option<&crate::config::RateLimit>,
+    ) -> anyhow::Result<RateLimitLayer> {
+        let quota = match limit {
+            Some(limit) => {
+                let mut quota = Quota::parse(&limit.quota).ok_or_else(|| {
+                    anyhow::anyhow!("Invalid {} rate limit: {} (expected e.g. 30/min)", group, limit.quota)
+                })?;
+                if let Some(burst) = limit.burst {
+                    quota.burst = burst;
+                }
+                info!("Rate limit for {}: {} ({} burst)", group, limit.quota, quota.burst);
+                Some(quota)
+            }
+            None => None,
+        };
+        Ok(RateLimitLayer {
+            limiter: Arc::new(Limiter {
+                group,
+                quota,
+                trusted_proxies: self.trusted_proxies.clone(),
+                buckets: Mutex::new(HashMap::new()),
+            }),
+        })
+    }
+}
+
+// A CIDR, or a single address
+pub fn parse_trusted_proxy(entry: &str) -> Option<IpNet> {
+    let entry = entry.trim();
+    IpNet::from_str(entry)
+        .or_else(|_| IpAddr::from_str(entry).map(IpNet::from))
+        .ok()
+}
+
+#[derive(Clone)]
+pub struct RateLimitLayer {
+    limiter: Arc<Limiter>,
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimit<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimit {
+            inner,
+            limiter: self.limiter.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimit<S> {
+    inner: S,
+    limiter: Arc<Limiter>,
+}
+
+impl<S> Service<Request> for RateLimit<S>
+where
+    S: Service<Request, Response = Response> + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        let Some(quota) = self.limiter.quota else {
+            return Box::pin(self.inner.call(request));
+        };
+        let key = self.limiter.client_key(&request);
+        let policy = format!("{};w={}", quota.burst, quota.period.as_secs());
+
+        match self.limiter.check(&quota, &key) {
+            Decision::Allowed { remaining, reset } => {
+                let future = self.inner.call(request);
+                Box::pin(async move {
+                    let mut response = future.await?;
+                    set_headers(response.headers_mut(), &policy, quota.burst, remaining, reset);
+                    Ok(response)
+                })
+            }
+            Decision::Limited { retry_after, reset } => {
+                warn!("Rate limit exceeded for {} on {}", key, self.limiter.group);
+                let mut response = ApiError::RateLimited { retry_after }.into_response();
+                set_headers(response.headers_mut(), &policy, quota.burst, 0, reset);
+                Box::pin(async move { Ok(response) })
+            }
+        }
+    }
+}
+
+fn set_headers(headers: &mut HeaderMap, policy: &str, limit: u32, remaining: u32, reset: u64) {
+    headers.insert("RateLimit-Limit", HeaderValue::from(limit));
+    headers.insert("RateLimit-Remaining", HeaderValue::from(remaining));
+    headers.insert("RateLimit-Reset", HeaderValue::from(reset));
+    if let Ok(val) = HeaderValue::from_str(policy) {
+        headers.insert("RateLimit-Policy", val);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Limits, RateLimit as RateLimitConfig};
+    use axum::{body::Body, http::StatusCode, routing::get, Router};
+    use tower::ServiceExt;
+
+    fn ip(v: &str) -> IpAddr {
+        v.parse().unwrap()
+    }
+
+    fn forwarded(values: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for v in values {
+            headers.append("x-forwarded-for", HeaderValue::from_str(v).unwrap());
+        }
+        headers
+    }
+
+    fn limiter(quota: &str, burst: Option<u32>, trusted: &[&str]) -> RateLimitLayer {
+        let limits = Limits {
+            trusted_proxies: trusted.iter().map(|t| t.to_string()).collect(),
+            ..Default::default()
+        };
+        let limit = RateLimitConfig {
+            quota: quota.to_string(),
+            burst,
+        };
+        RateLimits::from_config(&limits).unwrap().layer("pulls", Some(&limit)).unwrap()
+    }
+
+    #[test]
+    fn client_ip_walks_forwarded_for_from_the_right() {
+        let trusted = [parse_trusted_proxy("10.0.0.0/8").unwrap(), parse_trusted_proxy("192.0.2.1").unwrap()];
+        let peer = ip("10.0.0.1");
+
+        // The rightmost untrusted hop is the client, whatever comes before it
+        let headers = forwarded(&["198.51.100.7, 203.0.113.9, 10.1.2.3"]);
+        assert_eq!(client_ip(peer, &headers, &trusted), ip("203.0.113.9"));
+        // Hops spread over several headers
+        let headers = forwarded(&["198.51.100.7", "203.0.113.9", "192.0.2.1"]);
+        assert_eq!(client_ip(peer, &headers, &trusted), ip("203.0.113.9"));
+        // Only trusted hops: the leftmost one
+        let headers = forwarded(&["10.9.9.9, 10.1.2.3"]);
+        assert_eq!(client_ip(peer, &headers, &trusted), ip("10.9.9.9"));
+        // A hop that is not an address stops the walk at the last one read
+        let headers = forwarded(&["198.51.100.7, unknown, 10.1.2.3"]);
+        assert_eq!(client_ip(peer, &headers, &trusted), ip("10.1.2.3"));
+        // No header: the proxy itself
+        assert_eq!(client_ip(peer, &HeaderMap::new(), &trusted), peer);
+    }
+
+    #[test]
+    fn client_ip_ignores_forwarded_for_from_untrusted_peers() {
+        let trusted = [parse_trusted_proxy("10.0.0.0/8").unwrap()];
+        let headers = forwarded(&["203.0.113.9"]);
+        assert_eq!(client_ip(ip("198.51.100.7"), &headers, &trusted), ip("198.51.100.7"));
+        assert_eq!(client_ip(ip("10.0.0.1"), &headers, &[]), ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn buckets_allow_the_burst_then_refill() {
+        let layer = limiter("60/min", Some(3), &[]);
+        let limiter = &layer.limiter;
+        let quota = limiter.quota.unwrap();
+
+        for remaining in [2, 1, 0] {
+            assert!(matches!(limiter.check(&quota, "ip:a"), Decision::Allowed { remaining: r, .. } if r == remaining));
+        }
+        let Decision::Limited { retry_after, reset } = limiter.check(&quota, "ip:a") else {
+            panic!("burst exceeded but allowed");
+        };
+        // One token a second, three to refill
+        assert_eq!((retry_after, reset), (1, 3));
+        // Other clients have their own bucket
+        assert!(matches!(limiter.check(&quota, "ip:b"), Decision::Allowed { remaining: 2, .. }));
+
+        // Refill is capped at the burst
+        let rewind = |by: Duration| {
+            let mut buckets = limiter.buckets.lock().unwrap();
+            let bucket = buckets.get_mut("ip:a").unwrap();
+            bucket.updated -= by;
+        };
+        rewind(Duration::from_millis(2500));
+        assert!(matches!(limiter.check(&quota, "ip:a"), Decision::Allowed { remaining: 1, .. }));
+        rewind(Duration::from_secs(3600));
+        assert!(matches!(limiter.check(&quota, "ip:a"), Decision::Allowed { remaining: 2, .. }));
+    }
+
+    #[test]
+    fn quotas_parse() {
+        let quota = Quota::parse(" 30 / min ").unwrap();
+        assert_eq!((quota.requests, quota.period, quota.burst), (30, Duration::from_secs(60), 30));
+        assert_eq!(Quota::parse("5/h").unwrap().period, Duration::from_secs(3600));
+        for invalid in ["", "30", "0/min", "30/day", "x/s"] {
+            assert!(Quota::parse(invalid).is_none(), "{}", invalid);
+        }
+    }
+
+    #[tokio::test]
+    async fn responses_carry_rate_limit_headers() {
+        let app = Router::new()
+            .route("/api/pull", get(|| async { "ok" }))
+            .route_layer(limiter("2/min", None, &["10.0.0.0/8"]));
+        let call = |peer: &str, xff: &str| {
+            let mut request = axum::http::Request::get("/api/pull")
+                .header("x-forwarded-for", xff)
+                .body(Body::empty())
+                .unwrap();
+            request.extensions_mut().insert(ConnectInfo(SocketAddr::new(ip(peer), 40000)));
+            app.clone().oneshot(request)
+        };
+        let header = |response: &Response, name: &str| response.headers()[name].to_str().unwrap().to_string();
+
+        let response = call("10.0.0.1", "203.0.113.9").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header(&response, "RateLimit-Limit"), "2");
+        assert_eq!(header(&response, "RateLimit-Remaining"), "1");
+        assert_eq!(header(&response, "RateLimit-Reset"), "30");
+        assert_eq!(header(&response, "RateLimit-Policy"), "2;w=60");
+        assert!(response.headers().get("Retry-After").is_none());
+
+        // An untrusted peer cannot pick a fresh bucket by spoofing the header
+        for xff in ["198.51.100.1", "198.51.100.2"] {
+            let response = call("192.0.2.50", xff).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        let response = call("192.0.2.50", "198.51.100.3").await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(header(&response, "RateLimit-Remaining"), "0");
+        assert_eq!(header(&response, "Retry-After"), "30");
+        assert_eq!(header(&response, "RateLimit-Reset"), "60");
+
+        // The client behind the trusted proxy still has its token
+        let response = call("10.0.0.2", "203.0.113.9").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header(&response, "RateLimit-Remaining"), "0");
+    }
+}
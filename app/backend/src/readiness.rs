@@ -0,0 +1,400 @@
+use serde::Serialize;
+use std::{
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::{process::Command, sync::Mutex, time::timeout};
+use tracing::{info_span, warn, Instrument};
+use utoipa::ToSchema;
+
+use crate::{
+    config::{self, LiveConfig},
+    registry,
+};
+
+const REGISTRY_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Readiness checks. Tool and registry probes are cached for `cache.readiness` so
+// frequent Kubernetes probes do not spawn processes or hit registries each time;
+// disk space is cheap and checked on every call.
+pub struct Readiness {
+    skopeo_path: String,
+    helm_path: String,
+    client: reqwest::Client,
+    temp_dir: PathBuf,
+    // Thresholds, probed registries and cache lifetime, reloadable
+    config: Arc<LiveConfig>,
+    tools: Mutex<Option<(Instant, Tools)>>,
+    upstreams: Mutex<Option<(Instant, Vec<Upstream>)>>,
+}
+
+#[derive(Clone, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Tool {
+    pub available: bool,
+    pub version: Option<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Tools {
+    pub skopeo: Tool,
+    pub helm: Tool,
+    pub checked_at: String,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Disk {
+    pub path: String,
+    pub ok: bool,
+    pub free_bytes: Option<u64>,
+    pub total_bytes: Option<u64>,
+    pub free_inodes: Option<u64>,
+    pub total_inodes: Option<u64>,
+    pub min_free_bytes: u64,
+    pub min_free_inodes: u64,
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Upstream {
+    pub registry: String,
+    pub reachable: bool,
+    pub status: Option<u16>,
+    pub latency_ms: Option<u64>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+#[schema(as = ReadinessReport)]
+pub struct Report {
+    pub ready: bool,
+    pub reasons: Vec<String>,
+    pub tools: Tools,
+    pub disk: Disk,
+    pub registries: Vec<Upstream>,
+    pub config: config::Status,
+}
+
+impl Disk {
+    fn measure(&mut self, free_bytes: u64, total_bytes: u64, free_inodes: u64, total_inodes: u64) {
+        self.free_bytes = Some(free_bytes);
+        self.total_bytes = Some(total_bytes);
+        self.free_inodes = Some(free_inodes);
+        self.total_inodes = Some(total_inodes);
+        // Filesystems without an inode limit report zero inodes
+        self.ok = free_bytes >= self.min_free_bytes && (total_inodes == 0 || free_inodes >= self.min_free_inodes);
+    }
+}
+
+impl Readiness {
+    pub fn new(skopeo_path: &str, helm_path: &str, client: reqwest::Client, config: Arc<LiveConfig>) -> Self {
+        Self {
+            skopeo_path: skopeo_path.to_string(),
+            helm_path: helm_path.to_string(),
+            client,
+            temp_dir: crate::janitor::temp_dir(),
+            config,
+            tools: Mutex::new(None),
+            upstreams: Mutex::new(None),
+        }
+    }
+
+    // Tool versions, from the cache while it is fresh
+    pub async fn tools(&self) -> Tools {
+        let config = self.config.current();
+        let mut cached = self.tools.lock().await;
+        if let Some((at, tools)) = cached.as_ref() {
+            if at.elapsed() < config.cache.readiness {
+                return tools.clone();
+            }
+        }
+        let timeout = config.timeouts.tool_check;
+        let (skopeo, helm) = tokio::join!(
+            tool_version(&self.skopeo_path, &["--version"], timeout).instrument(info_span!("skopeo.version")),
+            tool_version(&self.helm_path, &["version", "--short"], timeout).instrument(info_span!("helm.version")),
+        );
+        let tools = Tools {
+            skopeo,
+            helm,
+            checked_at: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+        };
+        *cached = Some((Instant::now(), tools.clone()));
+        tools
+    }
+
+    pub fn disk(&self) -> Disk {
+        let limits = self.config.current().limits.clone();
+        let mut disk = Disk {
+            path: self.temp_dir.display().to_string(),
+            ok: true,
+            free_bytes: None,
+            total_bytes: None,
+            free_inodes: None,
+            total_inodes: None,
+            min_free_bytes: limits.min_free_space,
+            min_free_inodes: limits.min_free_inodes,
+            error: None,
+        };
+        match rustix::fs::statvfs(&self.temp_dir) {
+            Ok(stat) => disk.measure(
+                stat.f_bavail.saturating_mul(stat.f_frsize),
+                stat.f_blocks.saturating_mul(stat.f_frsize),
+                stat.f_favail,
+                stat.f_files,
+            ),
+            Err(e) => {
+                disk.ok = false;
+                disk.error = Some(e.to_string());
+            }
+        }
+        disk
+    }
+
+    // Reachability of the configured registries, from the cache while it is fresh
+    pub async fn registries(&self) -> Vec<Upstream> {
+        let config = self.config.current();
+        let mut cached = self.upstreams.lock().await;
+        if let Some((at, upstreams)) = cached.as_ref() {
+            if at.elapsed() < config.cache.readiness {
+                return upstreams.clone();
+            }
+        }
+        // Probed concurrently, reported in configuration order
+        let probes: Vec<_> = config
+            .registries
+            .iter()
+            .map(|r| tokio::spawn(probe(self.client.clone(), r.host.clone())))
+            .collect();
+        let mut upstreams = Vec::with_capacity(probes.len());
+        for (probe, registry) in probes.into_iter().zip(&config.registries) {
+            upstreams.push(probe.await.unwrap_or_else(|e| Upstream {
+                registry: registry.host.clone(),
+                reachable: false,
+                status: None,
+                latency_ms: None,
+                error: Some(e.to_string()),
+            }));
+        }
+        *cached = Some((Instant::now(), upstreams.clone()));
+        upstreams
+    }
+
+    // Ready when both tools run and the temp directory has room. Registries
+    // are reported but never fail readiness: an upstream outage should not
+    // take every replica out of the load balancer.
+    pub async fn check(&self) -> (Tools, Disk, Vec<String>) {
+        let tools = self.tools().await;
+        let disk = self.disk();
+        let mut reasons = Vec::new();
+        if !tools.skopeo.available {
+            reasons.push("skopeo not available".to_string());
+        }
+        if !tools.helm.available {
+            reasons.push("helm not available".to_string());
+        }
+        if !disk.ok {
+            reasons.push(format!("low disk space in {}", disk.path));
+        }
+        for reason in &reasons {
+            warn!("Readiness check failed: {}", reason);
+        }
+        (tools, disk, reasons)
+    }
+
+    pub async fn report(&self) -> Report {
+        let ((tools, disk, reasons), registries) = tokio::join!(self.check(), self.registries());
+        Report {
+            ready: reasons.is_empty(),
+            reasons,
+            tools,
+            disk,
+            registries,
+            config: self.config.status(),
+        }
+    }
+}
+
+async fn tool_version(program: &str, args: &[&str], limit: Duration) -> Tool {
+    let mut cmd = Command::new(program);
+    cmd.args(args).kill_on_drop(true);
+    match timeout(limit, cmd.output()).await {
+        Ok(Ok(output)) if output.status.success() => Tool {
+            available: true,
+            version: String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .next()
+                .map(|l| l.trim().to_string())
+                .filter(|l| !l.is_empty()),
+            error: None,
+        },
+        Ok(Ok(output)) => Tool {
+            available: false,
+            version: None,
+            error: Some(format!(
+                "exited with {}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )),
+        },
+        Ok(Err(e)) => Tool {
+            available: false,
+            version: None,
+            error: Some(e.to_string()),
+        },
+        Err(_) => Tool {
+            available: false,
+            version: None,
+            error: Some(format!("timed out after {:?}", limit)),
+        },
+    }
+}
+
+// Any HTTP answer from `/v2/`, usually 401, means the registry is reachable
+async fn probe(client: reqwest::Client, registry: String) -> Upstream {
+    let url = format!("{}/v2/", registry::base_url(&registry));
+    let start = Instant::now();
+    let result = client.get(&url).timeout(REGISTRY_TIMEOUT).send().await;
+    let latency_ms = start.elapsed().as_millis() as u64;
+    match result {
+        Ok(response) => Upstream {
+            registry,
+            reachable: true,
+            status: Some(response.status().as_u16()),
+            latency_ms: Some(latency_ms),
+            error: None,
+        },
+        Err(e) => Upstream {
+            registry,
+            reachable: false,
+            status: None,
+            latency_ms: None,
+            error: Some(error_chain(&e)),
+        },
+    }
+}
+
+// reqwest keeps the useful part (refused, DNS, TLS) in the source chain
+fn error_chain(e: &dyn std::error::Error) -> String {
+    let mut message = e.to_string();
+    let mut source = e.source();
+    while let Some(cause) = source {
+        message.push_str(": ");
+        message.push_str(&cause.to_string());
+        source = cause.source();
+    }
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Cache, Config, Limits};
+    use std::os::unix::fs::PermissionsExt;
+
+    fn readiness(dir: &std::path::Path, config: Config) -> Readiness {
+        let config = Arc::new(LiveConfig::from_config(None, config).unwrap());
+        let mut readiness = Readiness::new("skopeo", "helm", reqwest::Client::new(), config);
+        readiness.temp_dir = dir.to_path_buf();
+        readiness
+    }
+
+    fn limits(min_free_space: u64, min_free_inodes: u64) -> Config {
+        Config {
+            limits: Limits {
+                min_free_space,
+                min_free_inodes,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    // Logs each run next to itself
+    fn tool(dir: &std::path::Path, name: &str) -> String {
+        let path = dir.join(name);
+        std::fs::write(&path, format!("#!/bin/sh\necho run >> \"$0.log\"\necho '{} version 1.2.3'\n", name)).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path.display().to_string()
+    }
+
+    fn runs(tool: &str) -> usize {
+        std::fs::read_to_string(format!("{}.log", tool)).map(|log| log.lines().count()).unwrap_or(0)
+    }
+
+    #[test]
+    fn disk_thresholds() {
+        let gib = 1 << 30;
+        // (free bytes, free inodes, total inodes, ready)
+        let cases = [
+            (gib, 5000, 10000, true),
+            (gib, 1000, 10000, true),
+            (gib - 1, 5000, 10000, false),
+            (gib, 999, 10000, false),
+            (gib, 0, 0, true),
+            (gib - 1, 0, 0, false),
+        ];
+        let dir = tempfile::tempdir().unwrap();
+        let mut disk = readiness(dir.path(), limits(gib, 1000)).disk();
+        for (free_bytes, free_inodes, total_inodes, ready) in cases {
+            disk.measure(free_bytes, 2 * gib, free_inodes, total_inodes);
+            assert_eq!(disk.ok, ready, "{} bytes, {}/{} inodes", free_bytes, free_inodes, total_inodes);
+        }
+    }
+
+    #[tokio::test]
+    async fn checks_the_temp_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let disk = readiness(dir.path(), limits(0, 0)).disk();
+        assert!(disk.ok && disk.error.is_none());
+        assert!(disk.free_bytes.unwrap() > 0 && disk.total_bytes.unwrap() >= disk.free_bytes.unwrap());
+
+        let full = readiness(dir.path(), limits(u64::MAX, 0));
+        assert!(!full.disk().ok);
+        let (_, _, reasons) = full.check().await;
+        assert!(reasons.contains(&format!("low disk space in {}", dir.path().display())));
+
+        let missing = readiness(&dir.path().join("missing"), limits(0, 0)).disk();
+        assert!(!missing.ok && missing.error.is_some());
+    }
+
+    #[tokio::test]
+    async fn caches_tool_checks() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = |readiness| Config {
+            cache: Cache {
+                readiness,
+                ..Default::default()
+            },
+            ..limits(0, 0)
+        };
+        let mut cached = readiness(dir.path(), config(Duration::from_secs(60)));
+        cached.skopeo_path = tool(dir.path(), "skopeo");
+        cached.helm_path = tool(dir.path(), "helm");
+
+        let first = cached.tools().await;
+        assert_eq!(first.skopeo.version.as_deref(), Some("skopeo version 1.2.3"));
+        assert_eq!(first.helm.version.as_deref(), Some("helm version 1.2.3"));
+        for _ in 0..3 {
+            let tools = cached.tools().await;
+            assert_eq!(tools.checked_at, first.checked_at);
+        }
+        assert_eq!((runs(&cached.skopeo_path), runs(&cached.helm_path)), (1, 1));
+
+        // Expired entries are checked again
+        let mut uncached = readiness(dir.path(), config(Duration::ZERO));
+        uncached.skopeo_path = cached.skopeo_path.clone();
+        uncached.helm_path = dir.path().join("missing").display().to_string();
+        uncached.tools().await;
+        let tools = uncached.tools().await;
+        assert_eq!(runs(&cached.skopeo_path), 3);
+        assert!(tools.skopeo.available && !tools.helm.available);
+        let (_, _, reasons) = uncached.check().await;
+        assert_eq!(reasons, ["helm not available"]);
+    }
+}
@@ -0,0 +1,329 @@
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use serde_yaml::Value;
+use std::io::Read;
+use std::path::Path;
+
+// Bytes unpacked while relocating a chart, vendored subcharts included, so a
+// small archive cannot expand without bound
+const MAX_UNPACKED: u64 = 256 * 1024 * 1024;
+
+// Where relocated images are pushed: `harbor.local/mirror` becomes
+// registry `harbor.local` and repository prefix `mirror`.
+pub struct Target {
+    registry: String,
+    prefix: String,
+}
+
+impl Target {
+    pub fn parse(target: &str) -> Option<Self> {
+        let target = target
+            .trim()
+            .trim_start_matches("oci://")
+            .trim_start_matches("docker://")
+            .trim_end_matches('/');
+        if target.is_empty() || target.contains('@') {
+            return None;
+        }
+        let (registry, prefix) = target.split_once('/').unwrap_or((target, ""));
+        Some(Self {
+            registry: registry.to_string(),
+            prefix: prefix.to_string(),
+        })
+    }
+
+    fn repository(&self, repository: &str) -> String {
+        if self.prefix.is_empty() {
+            repository.to_string()
+        } else {
+            format!("{}/{}", self.prefix, repository)
+        }
+    }
+}
+
+pub struct Relocation {
+    pub archive: Vec<u8>,
+    // (original, relocated) image references, subcharts included
+    pub images: Vec<(String, String)>,
+}
+
+// Rewrite the image references in every values.yaml of a packaged chart, recursing
+// into subcharts vendored as .tgz under charts/, and repackage the result.
+pub fn relocate_chart(archive: &[u8], target: &Target) -> anyhow::Result<Relocation> {
+    let mut images = Vec::new();
+    let mut budget = MAX_UNPACKED;
+    let archive = relocate_archive(archive, target, &mut images, &mut budget)?;
+    Ok(Relocation { archive, images })
+}
+
+fn relocate_archive(
+    archive: &[u8],
+    target: &Target,
+    images: &mut Vec<(String, String)>,
+    budget: &mut u64,
+) -> anyhow::Result<Vec<u8>> {
+    let mut input = tar::Archive::new(GzDecoder::new(archive));
+    let mut output = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+
+    for entry in input.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        let mut header = entry.header().clone();
+        let mut data = Vec::new();
+        (&mut entry).take(*budget + 1).read_to_end(&mut data)?;
+        if data.len() as u64 > *budget {
+            anyhow::bail!("chart exceeds {} MiB unpacked", MAX_UNPACKED >> 20);
+        }
+        *budget -= data.len() as u64;
+
+        if header.entry_type().is_file() {
+            if is_values_file(&path) {
+                data = relocate_values(&data, target, images)?;
+            } else if is_vendored_subchart(&path) {
+                data = relocate_archive(&data, target, images, budget)?;
+            }
+        }
+
+        header.set_size(data.len() as u64);
+        output.append_data(&mut header, &path, data.as_slice())?;
+    }
+
+    Ok(output.into_inner()?.finish()?)
+}
+
+fn is_values_file(path: &Path) -> bool {
+    path.file_name().is_some_and(|n| n == "values.yaml" || n == "values.yml")
+}
+
+fn is_vendored_subchart(path: &Path) -> bool {
+    path.extension().is_some_and(|e| e == "tgz")
+        && path
+            .parent()
+            .and_then(Path::file_name)
+            .is_some_and(|n| n == "charts")
+}
+
+fn relocate_values(
+    data: &[u8],
+    target: &Target,
+    images: &mut Vec<(String, String)>,
+) -> anyhow::Result<Vec<u8>> {
+    let mut values: Value = serde_yaml::from_slice(data)?;
+    let before = images.len();
+    relocate_value(&mut values, None, target, images);
+
+    // Re-serializing drops comments, so untouched files are kept byte for byte
+    if images.len() == before {
+        return Ok(data.to_vec());
+    }
+    Ok(serde_yaml::to_string(&values)?.into_bytes())
+}
+
+fn relocate_value(
+    value: &mut Value,
+    key: Option<&str>,
+    target: &Target,
+    images: &mut Vec<(String, String)>,
+) {
+    match value {
+        Value::Mapping(map) => {
+            if is_image_map(map, key) {
+                relocate_image_map(map, target, images);
+            }
+            for (k, v) in map.iter_mut() {
+                relocate_value(v, k.as_str(), target, images);
+            }
+        }
+        Value::Sequence(seq) => {
+            for v in seq {
+                relocate_value(v, key, target, images);
+            }
+        }
+        // `image: nginx:1.25` style values
+        Value::String(s) if key.is_some_and(is_image_key) && looks_like_image(s) => {
+            let relocated = format!(
+                "{}/{}",
+                target.registry,
+                target.repository(strip_registry(s))
+            );
+            images.push((s.clone(), relocated.clone()));
+            *s = relocated;
+        }
+        _ => {}
+    }
+}
+
+fn is_image_key(key: &str) -> bool {
+    key.to_lowercase().ends_with("image")
+}
+
+// `{registry, repository, tag}` maps, as used by most charts (bitnami included)
+fn is_image_map(map: &serde_yaml::Mapping, key: Option<&str>) -> bool {
+    let has = |k: &str| map.get(k).is_some_and(Value::is_string);
+    has("repository") && (key.is_some_and(is_image_key) || has("registry") || map.contains_key("tag"))
+}
+
+fn relocate_image_map(
+    map: &mut serde_yaml::Mapping,
+    target: &Target,
+    images: &mut Vec<(String, String)>,
+) {
+    let repository = map.get("repository").and_then(Value::as_str).unwrap_or_default().to_string();
+    if repository.is_empty() {
+        return;
+    }
+    let registry = map
+        .get("registry")
+        .and_then(Value::as_str)
+        .filter(|r| !r.is_empty())
+        .map(str::to_string);
+
+    let (original, path) = match &registry {
+        Some(registry) => (format!("{}/{}", registry, repository), repository.as_str()),
+        None => (repository.clone(), strip_registry(&repository)),
+    };
+    let relocated_repository = target.repository(path);
+
+    match registry {
+        Some(_) => {
+            map.insert("registry".into(), target.registry.clone().into());
+            map.insert("repository".into(), relocated_repository.clone().into());
+        }
+        None => {
+            map.insert(
+                "repository".into(),
+                format!("{}/{}", target.registry, relocated_repository).into(),
+            );
+        }
+    }
+    images.push((original, format!("{}/{}", target.registry, relocated_repository)));
+}
+
+fn looks_like_image(s: &str) -> bool {
+    !s.is_empty()
+        && !s.contains("{{")
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || "./:@_-".contains(c))
+}
+
+// Drop the registry host from a reference, keeping the repository path and tag
+fn strip_registry(reference: &str) -> &str {
+    match reference.split_once('/') {
+        Some((first, rest))
+            if first.contains('.') || first.contains(':') || first == "localhost" =>
+        {
+            rest
+        }
+        _ => reference,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn package(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+        for (path, data) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            builder.append_data(&mut header, path, *data).unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    fn unpack(archive: &[u8]) -> BTreeMap<String, Vec<u8>> {
+        let mut files = BTreeMap::new();
+        for entry in tar::Archive::new(GzDecoder::new(archive)).entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data).unwrap();
+            files.insert(entry.path().unwrap().display().to_string(), data);
+        }
+        files
+    }
+
+    fn values(files: &BTreeMap<String, Vec<u8>>, path: &str) -> Value {
+        serde_yaml::from_slice(&files[path]).unwrap()
+    }
+
+    fn target() -> Target {
+        Target::parse("oci://harbor.local/mirror/").unwrap()
+    }
+
+    #[test]
+    fn relocates_image_strings() {
+        let chart = package(&[
+            ("web/Chart.yaml", b"name: web\nversion: 1.0.0\n"),
+            ("web/values.yaml", b"image: docker.io/library/nginx:1.25\nsidecarImage: busybox:1.36\nname: web\n"),
+        ]);
+        let relocation = relocate_chart(&chart, &target()).unwrap();
+        let files = unpack(&relocation.archive);
+        let values = values(&files, "web/values.yaml");
+        assert_eq!(values["image"], "harbor.local/mirror/library/nginx:1.25");
+        assert_eq!(values["sidecarImage"], "harbor.local/mirror/busybox:1.36");
+        assert_eq!(values["name"], "web");
+        assert_eq!(
+            relocation.images,
+            [
+                ("docker.io/library/nginx:1.25".to_string(), "harbor.local/mirror/library/nginx:1.25".to_string()),
+                ("busybox:1.36".to_string(), "harbor.local/mirror/busybox:1.36".to_string()),
+            ]
+        );
+        assert_eq!(files["web/Chart.yaml"], b"name: web\nversion: 1.0.0\n");
+    }
+
+    #[test]
+    fn relocates_image_maps() {
+        let chart = package(&[(
+            "web/values.yaml",
+            b"image:\n  registry: docker.io\n  repository: bitnami/nginx\n  tag: 1.25.3\n\
+metrics:\n  image:\n    repository: quay.io/prometheus/nginx-exporter\n    tag: v1.1.0\n",
+        )]);
+        let relocation = relocate_chart(&chart, &target()).unwrap();
+        let values = values(&unpack(&relocation.archive), "web/values.yaml");
+        assert_eq!(values["image"]["registry"], "harbor.local");
+        assert_eq!(values["image"]["repository"], "mirror/bitnami/nginx");
+        assert_eq!(values["image"]["tag"], "1.25.3");
+        assert_eq!(values["metrics"]["image"]["repository"], "harbor.local/mirror/prometheus/nginx-exporter");
+        assert_eq!(values["metrics"]["image"]["tag"], "v1.1.0");
+        let originals: Vec<&str> = relocation.images.iter().map(|(o, _)| o.as_str()).collect();
+        assert_eq!(originals, ["docker.io/bitnami/nginx", "quay.io/prometheus/nginx-exporter"]);
+    }
+
+    #[test]
+    fn relocates_vendored_subcharts() {
+        let subchart = package(&[("redis/values.yaml", b"image:\n  repository: redis\n  tag: 7.2\n")]);
+        let chart = package(&[
+            ("web/values.yaml", b"image: nginx:1.25\n"),
+            ("web/charts/redis-1.0.0.tgz", &subchart),
+        ]);
+        let relocation = relocate_chart(&chart, &target()).unwrap();
+        let files = unpack(&relocation.archive);
+        let subchart = unpack(&files["web/charts/redis-1.0.0.tgz"]);
+        assert_eq!(values(&subchart, "redis/values.yaml")["image"]["repository"], "harbor.local/mirror/redis");
+        assert_eq!(relocation.images.len(), 2);
+    }
+
+    #[test]
+    fn keeps_values_without_images_byte_for_byte() {
+        let original: &[u8] = b"# Replicas of the web tier\nreplicaCount: 2   # keep it even\nservice:\n  port: 80\n";
+        let chart = package(&[("web/values.yaml", original)]);
+        let relocation = relocate_chart(&chart, &target()).unwrap();
+        assert!(relocation.images.is_empty());
+        assert_eq!(unpack(&relocation.archive)["web/values.yaml"], original);
+    }
+
+    #[test]
+    fn caps_unpacked_size_of_subcharts() {
+        let bomb = vec![0u8; 1 << 20];
+        let subchart = package(&[("big/templates/blob.yaml", &bomb)]);
+        let chart = package(&[("web/charts/big-1.0.0.tgz", &subchart)]);
+        assert!(subchart.len() < 64 * 1024);
+
+        let error = relocate_archive(&chart, &target(), &mut Vec::new(), &mut (512 * 1024)).unwrap_err();
+        assert!(error.to_string().contains("unpacked"), "{}", error);
+        assert!(relocate_archive(&chart, &target(), &mut Vec::new(), &mut (2 << 20)).is_ok());
+    }
+}
@@ -0,0 +1,118 @@
+use axum::{
+    extract::Request,
+    http::{HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use uuid::Uuid;
+
+pub static X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+// Longer or non-printable client IDs are replaced rather than logged
+const MAX_LEN: usize = 128;
+
+// ID of the current request, carried in the request extensions
+#[derive(Clone, Debug)]
+pub struct RequestId(pub String);
+
+tokio::task_local! {
+    // Same ID, for code without access to the request, such as error bodies
+    static CURRENT: String;
+}
+
+pub fn current() -> Option<String> {
+    CURRENT.try_with(Clone::clone).ok()
+}
+
+// Keep the caller's X-Request-Id when it is sane, generate one otherwise, and
+// echo it in the response
+pub async fn propagate(mut request: Request, next: Next) -> Response {
+    let id = request
+        .headers()
+        .get(&X_REQUEST_ID)
+        .and_then(|v| v.to_str().ok())
+        .map(str::trim)
+        .filter(|v| !v.is_empty() && v.len() <= MAX_LEN && v.bytes().all(|b| b.is_ascii_graphic()))
+        .map_or_else(|| Uuid::new_v4().to_string(), str::to_string);
+    request.extensions_mut().insert(RequestId(id.clone()));
+
+    let mut response = CURRENT.scope(id.clone(), next.run(request)).await;
+    if let Ok(val) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert(X_REQUEST_ID.clone(), val);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ApiError;
+    use axum::{body::Body, http::StatusCode, middleware, response::IntoResponse, routing::get, Extension, Router};
+    use tower::ServiceExt;
+
+    fn app() -> Router {
+        Router::new()
+            .route("/id", get(|Extension(id): Extension<RequestId>| async move { id.0 }))
+            .route("/error", get(|| async { ApiError::BadRequest("Invalid image".to_string()).into_response() }))
+            .layer(middleware::from_fn(propagate))
+    }
+
+    async fn call(path: &str, id: Option<&[u8]>) -> (Response, String) {
+        let mut request = axum::http::Request::get(path);
+        if let Some(id) = id {
+            request = request.header(&X_REQUEST_ID, HeaderValue::from_bytes(id).unwrap());
+        }
+        let response = app().oneshot(request.body(Body::empty()).unwrap()).await.unwrap();
+        let echoed = response.headers()[&X_REQUEST_ID].to_str().unwrap().to_string();
+        (response, echoed)
+    }
+
+    async fn text(response: Response) -> String {
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn keeps_sane_ids() {
+        let long = "a".repeat(MAX_LEN);
+        let cases = [("abc-123", "abc-123"), ("  trimmed  ", "trimmed"), (long.as_str(), long.as_str())];
+        for (id, expected) in cases {
+            let (response, echoed) = call("/id", Some(id.as_bytes())).await;
+            assert_eq!(echoed, expected, "{}", id);
+            assert_eq!(text(response).await, expected, "{}", id);
+        }
+    }
+
+    #[tokio::test]
+    async fn replaces_invalid_ids() {
+        let long = "a".repeat(MAX_LEN + 1);
+        let cases: [&[u8]; 6] = [b"", b"   ", long.as_bytes(), b"two words", b"tab\there", b"caf\xc3\xa9"];
+        for id in cases {
+            let (response, echoed) = call("/id", Some(id)).await;
+            assert!(Uuid::parse_str(&echoed).is_ok(), "{:?}", id);
+            assert_eq!(text(response).await, echoed, "{:?}", id);
+        }
+
+        // Generated per request
+        let (_, first) = call("/id", None).await;
+        let (_, second) = call("/id", None).await;
+        assert!(Uuid::parse_str(&first).is_ok());
+        assert_ne!(first, second);
+    }
+
+    #[tokio::test]
+    async fn error_bodies_carry_the_id() {
+        let (response, echoed) = call("/error", Some(b"trace-42")).await;
+        assert_eq!(echoed, "trace-42");
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = serde_json::from_str(&text(response).await).unwrap();
+        assert_eq!(body["requestId"], "trace-42");
+        assert_eq!(body["code"], "bad_request");
+
+        // Outside a request there is no ID to report
+        assert!(current().is_none());
+        let body = text(ApiError::BadRequest("Invalid image".to_string()).into_response()).await;
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert!(body.get("requestId").is_none());
+    }
+}
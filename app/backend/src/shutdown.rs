@@ -0,0 +1,282 @@
+use std::{
+    collections::HashSet,
+    future::Future,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing::{info, warn};
+
+use crate::config::LiveConfig;
+
+// Time given to interrupted subprocesses to be reaped once the grace period is over
+const KILL_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Shutdown sequence on SIGTERM or SIGINT: readiness fails and no new
+// connections are accepted, in-flight responses get the grace period to
+// finish, then remaining subprocesses are killed and temp artifacts removed.
+pub struct Shutdown {
+    // Grace period from `timeouts.shutdownGrace`, read when the signal arrives
+    config: Arc<LiveConfig>,
+    draining: CancellationToken,
+    abort: CancellationToken,
+    // skopeo and helm runs, killed when aborted
+    subprocesses: TaskTracker,
+    // Temp files and directories still owned by a request
+    artifacts: Mutex<HashSet<PathBuf>>,
+}
+
+impl Shutdown {
+    pub fn new(config: Arc<LiveConfig>) -> Self {
+        Self {
+            config,
+            draining: CancellationToken::new(),
+            abort: CancellationToken::new(),
+            subprocesses: TaskTracker::new(),
+            artifacts: Mutex::new(HashSet::new()),
+        }
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.is_cancelled()
+    }
+
+    // Resolves on the first SIGTERM or SIGINT, for `with_graceful_shutdown`
+    pub async fn signal(self: Arc<Self>) {
+        let ctrl_c = async {
+            if let Err(e) = tokio::signal::ctrl_c().await {
+                warn!("Failed to listen for SIGINT: {}", e);
+                std::future::pending::<()>().await;
+            }
+        };
+        #[cfg(unix)]
+        let terminate = async {
+            match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+                Ok(mut signal) => {
+                    signal.recv().await;
+                }
+                Err(e) => {
+                    warn!("Failed to listen for SIGTERM: {}", e);
+                    std::future::pending::<()>().await;
+                }
+            }
+        };
+        #[cfg(not(unix))]
+        let terminate = std::future::pending::<()>();
+
+        tokio::select! {
+            _ = ctrl_c => info!("Received SIGINT"),
+            _ = terminate => info!("Received SIGTERM"),
+        }
+        info!(
+            "Shutting down, draining in-flight requests for up to {:?}",
+            self.config.current().timeouts.shutdown_grace
+        );
+        self.draining.cancel();
+    }
+
+    // Resolves once the grace period after the shutdown signal is over
+    pub async fn grace_period_elapsed(&self) {
+        self.draining.cancelled().await;
+        tokio::time::sleep(self.config.current().timeouts.shutdown_grace).await;
+    }
+
+    // Wait for the server to drain, for no longer than the grace period after
+    // the signal, then finish
+    pub async fn drain(&self, server: impl Future<Output = std::io::Result<()>>) -> std::io::Result<()> {
+        tokio::select! {
+            result = server => result?,
+            _ = self.grace_period_elapsed() => {
+                warn!("Grace period elapsed, dropping remaining connections");
+            }
+        }
+        self.finish().await;
+        Ok(())
+    }
+
+    // Run a subprocess future, built with `kill_on_drop`, so that it is
+    // dropped (and its child killed) when the shutdown aborts
+    pub async fn subprocess<T>(
+        &self,
+        run: impl Future<Output = std::io::Result<T>>,
+    ) -> std::io::Result<T> {
+        let abort = self.abort.clone();
+        self.subprocesses
+            .track_future(async move {
+                tokio::select! {
+                    result = run => result,
+                    _ = abort.cancelled() => Err(std::io::Error::new(
+                        std::io::ErrorKind::Interrupted,
+                        "server shutting down",
+                    )),
+                }
+            })
+            .await
+    }
+
+    // Register a temp file or directory, removed when the returned guard is
+    // dropped, or at shutdown if the request still holds it then
+    pub fn track(self: &Arc<Self>, path: &Path) -> Artifact {
+        self.artifacts.lock().unwrap().insert(path.to_path_buf());
+        Artifact {
+            path: path.to_path_buf(),
+            owner: self.clone(),
+        }
+    }
+
+    pub fn is_tracked(&self, path: &Path) -> bool {
+        self.artifacts.lock().unwrap().contains(path)
+    }
+
+    // Kill remaining subprocesses, then remove the artifacts left behind
+    pub async fn finish(&self) {
+        self.abort.cancel();
+        self.subprocesses.close();
+        if !self.subprocesses.is_empty() {
+            info!("Killing {} running subprocesses", self.subprocesses.len());
+        }
+        if tokio::time::timeout(KILL_TIMEOUT, self.subprocesses.wait()).await.is_err() {
+            warn!("Subprocesses still running after {:?}", KILL_TIMEOUT);
+        }
+
+        let artifacts: Vec<PathBuf> = self.artifacts.lock().unwrap().drain().collect();
+        let mut removed = 0;
+        for path in artifacts {
+            let result = match tokio::fs::symlink_metadata(&path).await {
+                Ok(meta) if meta.is_dir() => tokio::fs::remove_dir_all(&path).await,
+                Ok(_) => tokio::fs::remove_file(&path).await,
+                Err(_) => continue,
+            };
+            match result {
+                Ok(()) => removed += 1,
+                Err(e) => warn!("Failed to remove {}: {}", path.display(), e),
+            }
+        }
+        if removed > 0 {
+            info!("Removed {} temporary artifacts", removed);
+        }
+    }
+}
+
+// Keeps a temp path registered until dropped, then removes it if it is still
+// there: a request abandoned by its client is dropped at whatever await it
+// was on, before its own cleanup runs
+pub struct Artifact {
+    path: PathBuf,
+    owner: Arc<Shutdown>,
+}
+
+impl Drop for Artifact {
+    fn drop(&mut self) {
+        self.owner.artifacts.lock().unwrap().remove(&self.path);
+        let _ = match std::fs::symlink_metadata(&self.path) {
+            Ok(meta) if meta.is_dir() => std::fs::remove_dir_all(&self.path),
+            Ok(_) => std::fs::remove_file(&self.path),
+            Err(_) => return,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, Timeouts};
+    use std::time::Instant;
+
+    fn shutdown(grace: Duration) -> Arc<Shutdown> {
+        let config = Config {
+            timeouts: Timeouts {
+                shutdown_grace: grace,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        Arc::new(Shutdown::new(Arc::new(LiveConfig::from_config(None, config).unwrap())))
+    }
+
+    fn artifact(shutdown: &Arc<Shutdown>, dir: &Path) -> (PathBuf, Artifact) {
+        let path = dir.join("image.tar");
+        std::fs::write(&path, b"layers").unwrap();
+        let artifact = shutdown.track(&path);
+        (path, artifact)
+    }
+
+    #[tokio::test]
+    async fn held_artifacts_delay_finish_until_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let shutdown = shutdown(Duration::from_secs(30));
+        let (path, artifact) = artifact(&shutdown, dir.path());
+        let (done, served) = tokio::sync::oneshot::channel::<()>();
+        // Stands in for a connection still streaming the artifact
+        let server = async move {
+            let _artifact = artifact;
+            let _ = served.await;
+            Ok(())
+        };
+
+        shutdown.draining.cancel();
+        let drain = tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move { shutdown.drain(server).await }
+        });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!drain.is_finished());
+        assert!(!shutdown.abort.is_cancelled());
+        assert!(path.exists() && shutdown.is_tracked(&path));
+
+        done.send(()).unwrap();
+        drain.await.unwrap().unwrap();
+        assert!(shutdown.abort.is_cancelled());
+        assert!(!path.exists() && !shutdown.is_tracked(&path));
+    }
+
+    #[tokio::test]
+    async fn aborts_after_the_grace_period() {
+        let dir = tempfile::tempdir().unwrap();
+        let grace = Duration::from_millis(300);
+        let shutdown = shutdown(grace);
+        let (path, artifact) = artifact(&shutdown, dir.path());
+
+        // Nothing is counted before the signal
+        let elapsed = tokio::time::timeout(grace * 2, shutdown.grace_period_elapsed()).await;
+        assert!(elapsed.is_err());
+
+        let child = tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move {
+                let sleep = tokio::process::Command::new("sleep").arg("30").kill_on_drop(true).status();
+                shutdown.subprocess(sleep).await
+            }
+        });
+        let started = Instant::now();
+        shutdown.draining.cancel();
+        shutdown.drain(std::future::pending()).await.unwrap();
+
+        assert!(started.elapsed() >= grace);
+        assert!(started.elapsed() < KILL_TIMEOUT);
+        assert!(shutdown.abort.is_cancelled());
+        let result = child.await.unwrap();
+        assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::Interrupted);
+        // Removed although the request still holds it, and dropping it later is harmless
+        assert!(!path.exists() && !shutdown.is_tracked(&path));
+        drop(artifact);
+    }
+
+    #[tokio::test]
+    async fn dropped_artifacts_are_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let shutdown = shutdown(Duration::from_secs(30));
+        let (path, artifact) = artifact(&shutdown, dir.path());
+        let nested = dir.path().join("chart");
+        std::fs::create_dir_all(nested.join("templates")).unwrap();
+        let directory = shutdown.track(&nested);
+
+        drop(artifact);
+        drop(directory);
+        assert!(!path.exists() && !shutdown.is_tracked(&path));
+        assert!(!nested.exists() && !shutdown.is_tracked(&nested));
+        // Finished subprocesses return their own result
+        assert_eq!(shutdown.subprocess(async { Ok(7) }).await.unwrap(), 7);
+    }
+}
@@ -0,0 +1,98 @@
+use base64::Engine;
+use std::path::{Path, PathBuf};
+use tokio::{io::AsyncWriteExt, process::Command};
+use uuid::Uuid;
+
+use crate::registry::Credentials;
+
+// Per-invocation containers auth file, readable by the backend user only and
+// removed when dropped. Keeps credentials out of the skopeo command line.
+pub struct AuthFile {
+    path: PathBuf,
+}
+
+impl AuthFile {
+    pub async fn create(registry: &str, creds: &Credentials) -> std::io::Result<Self> {
+        let auth = base64::engine::general_purpose::STANDARD
+            .encode(format!("{}:{}", creds.username, creds.password));
+        let content = serde_json::json!({"auths": {registry: {"auth": auth}}});
+
+        let path = crate::janitor::temp_dir().join(format!("auth-{}.json", Uuid::new_v4()));
+        let mut options = tokio::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        let mut file = options.open(&path).await?;
+        let auth_file = Self { path };
+        file.write_all(content.to_string().as_bytes()).await?;
+        file.flush().await?;
+        Ok(auth_file)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for AuthFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+// `skopeo copy [--authfile <file>] --digestfile <file> docker://<reference> <dest>`
+pub fn copy_command(
+    skopeo_path: &str,
+    reference: &str,
+    dest: &str,
+    auth_file: Option<&AuthFile>,
+    digest_file: &Path,
+) -> Command {
+    let mut cmd = Command::new(skopeo_path);
+    // Killed if the pull is abandoned, by the client or at shutdown
+    cmd.arg("copy").kill_on_drop(true);
+    // Manifest digest of the copied image, for the audit log
+    cmd.arg("--digestfile").arg(digest_file);
+    if let Some(auth_file) = auth_file {
+        cmd.arg("--authfile").arg(auth_file.path());
+    }
+    cmd.arg(format!("docker://{}", reference)).arg(dest);
+    cmd
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn copy_command_keeps_password_out_of_argv() {
+        let creds = Credentials::from_parts(Some("robot"), Some("s3cr3t-pw")).unwrap();
+        let auth_file = AuthFile::create("ghcr.io", &creds).await.unwrap();
+        let cmd = copy_command(
+            "skopeo",
+            "ghcr.io/org/app:1.0",
+            "docker-archive:/tmp/x.tar",
+            Some(&auth_file),
+            Path::new("/tmp/x.digest"),
+        );
+
+        let encoded = base64::engine::general_purpose::STANDARD.encode("robot:s3cr3t-pw");
+        for arg in cmd.as_std().get_args() {
+            let arg = arg.to_string_lossy();
+            assert!(!arg.contains("s3cr3t-pw") && !arg.contains(&encoded), "secret in argv: {}", arg);
+        }
+
+        let content = std::fs::read_to_string(auth_file.path()).unwrap();
+        assert!(content.contains(&encoded));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(auth_file.path()).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        let path = auth_file.path().to_path_buf();
+        drop(auth_file);
+        assert!(!path.exists());
+    }
+}
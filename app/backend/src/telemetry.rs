@@ -0,0 +1,189 @@
+use axum::{
+    body::{Body, Bytes, HttpBody},
+    extract::{MatchedPath, Request},
+    http::HeaderMap,
+    middleware::Next,
+    response::Response,
+};
+use hyper::body::{Frame, SizeHint};
+use opentelemetry::{global, propagation::Extractor, trace::TracerProvider as _, KeyValue};
+use opentelemetry_sdk::{propagation::TraceContextPropagator, runtime, trace::TracerProvider, Resource};
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+    time::Instant,
+};
+use tracing::{debug, field::Empty, info, info_span, Instrument, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+
+use crate::request_id::RequestId;
+
+// Keeps the OTLP pipeline alive; spans still buffered are flushed on shutdown
+pub struct Telemetry {
+    provider: Option<TracerProvider>,
+}
+
+impl Telemetry {
+    pub fn shutdown(&self) {
+        if let Some(provider) = &self.provider {
+            if let Err(e) = provider.shutdown() {
+                eprintln!("Failed to flush traces: {}", e);
+            }
+        }
+    }
+}
+
+// Log to stdout and, when OTEL_EXPORTER_OTLP_ENDPOINT (or the traces specific
+// OTEL_EXPORTER_OTLP_TRACES_ENDPOINT) is set, export spans over OTLP/HTTP.
+// Incoming W3C `traceparent` headers are honoured either way.
+pub fn init() -> anyhow::Result<Telemetry> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let rust_log = std::env::var("RUST_LOG").unwrap_or_else(|_| "info".to_string());
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(&rust_log));
+    // LOG_FORMAT=json: one object per line with event fields at the top level
+    // and the fields of enclosing spans (request_id, reference, ...) in `spans`
+    let json = std::env::var("LOG_FORMAT").is_ok_and(|f| f.eq_ignore_ascii_case("json"));
+    let (json_fmt, text_fmt) = if json {
+        let layer = tracing_subscriber::fmt::layer()
+            .json()
+            .flatten_event(true)
+            .with_current_span(false)
+            .with_span_list(true)
+            .with_writer(std::io::stdout)
+            .with_target(true);
+        (Some(layer), None)
+    } else {
+        let layer = tracing_subscriber::fmt::layer()
+            .with_writer(std::io::stdout)
+            .with_thread_ids(true)
+            .with_target(true);
+        (None, Some(layer))
+    };
+
+    let endpoint = ["OTEL_EXPORTER_OTLP_TRACES_ENDPOINT", "OTEL_EXPORTER_OTLP_ENDPOINT"]
+        .iter()
+        .find_map(|var| std::env::var(var).ok().filter(|v| !v.is_empty()));
+    let provider = match &endpoint {
+        Some(_) => {
+            let exporter = opentelemetry_otlp::SpanExporter::builder()
+                .with_http()
+                .build()
+                .map_err(|e| anyhow::anyhow!("Failed to create OTLP exporter: {}", e))?;
+            // OTEL_SERVICE_NAME and OTEL_RESOURCE_ATTRIBUTES take precedence
+            let mut resource = Resource::default();
+            if std::env::var("OTEL_SERVICE_NAME").is_err() {
+                resource = resource.merge(&Resource::new([KeyValue::new("service.name", "tessark-backend")]));
+            }
+            Some(
+                TracerProvider::builder()
+                    .with_batch_exporter(exporter, runtime::Tokio)
+                    .with_resource(resource)
+                    .build(),
+            )
+        }
+        None => None,
+    };
+    let otel = provider
+        .as_ref()
+        .map(|p| tracing_opentelemetry::layer().with_tracer(p.tracer("tessark-backend")));
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(json_fmt)
+        .with(text_fmt)
+        .with(otel)
+        .init();
+    if let Some(endpoint) = endpoint {
+        info!("Exporting traces over OTLP to {}", endpoint);
+    }
+    Ok(Telemetry { provider })
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|k| k.as_str()).collect()
+    }
+}
+
+// Root span of a request, continuing the caller's trace when it sent a
+// `traceparent` (the Next.js proxy does). Every log line of the request carries
+// its request ID through this span.
+pub async fn trace_request(request: Request, next: Next) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or_else(|| request.uri().path().to_string(), |p| p.as_str().to_string());
+    let method = request.method().clone();
+    let request_id = request
+        .extensions()
+        .get::<RequestId>()
+        .map(|RequestId(id)| id.clone())
+        .unwrap_or_default();
+    let span = info_span!(
+        "request",
+        otel.name = %format!("{} {}", method, route),
+        otel.kind = "server",
+        request_id = %request_id,
+        http.request.method = %method,
+        http.route = %route,
+        http.response.status_code = Empty,
+    );
+    let parent = global::get_text_map_propagator(|p| p.extract(&HeaderExtractor(request.headers())));
+    span.set_parent(parent);
+
+    let start = Instant::now();
+    let response = next.run(request).instrument(span.clone()).await;
+    let status = response.status().as_u16();
+    span.record("http.response.status_code", status);
+    let duration_ms = start.elapsed().as_millis() as u64;
+    span.in_scope(|| {
+        // Probes and scrapes would drown everything else at info level
+        if matches!(route.as_str(), "/health" | "/ready" | "/metrics") {
+            debug!(status, duration_ms, "Request completed");
+        } else {
+            info!(status, duration_ms, "Request completed");
+        }
+    });
+    response
+}
+
+// Body that keeps `span` open until the client has received everything, so
+// streaming time shows up in the trace
+pub fn traced_body(body: Body, span: Span) -> Body {
+    Body::new(TracedBody { inner: body, span })
+}
+
+struct TracedBody {
+    inner: Body,
+    span: Span,
+}
+
+impl HttpBody for TracedBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = &mut *self;
+        let _entered = this.span.enter();
+        Pin::new(&mut this.inner).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
@@ -0,0 +1,408 @@
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    Aes256Gcm, Nonce,
+};
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::{
+    path::{Path, PathBuf},
+    sync::RwLock,
+};
+use tracing::info;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::registry::{normalize_host, Credentials};
+
+const B64: base64::engine::GeneralPurpose = base64::engine::general_purpose::STANDARD;
+
+#[derive(thiserror::Error, Debug)]
+pub enum VaultError {
+    #[error("unknown credential: {0}")]
+    NotFound(String),
+    #[error("credential {0} is not bound to registry {1}")]
+    RegistryMismatch(String, String),
+    #[error("failed to access credential store: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("credential store cannot be decrypted: {0}")]
+    Crypto(String),
+    #[error("invalid credential store: {0}")]
+    Format(#[from] serde_json::Error),
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct StoredCredential {
+    pub id: String,
+    // Registry host the credential is bound to (docker.io, ghcr.io, harbor.local:8443)
+    pub registry: String,
+    pub username: String,
+    pub password: String,
+    #[serde(default)]
+    pub description: Option<String>,
+}
+
+// What the admin API returns: everything but the secret
+#[derive(Serialize, ToSchema)]
+pub struct CredentialSummary {
+    pub id: String,
+    pub registry: String,
+    pub username: String,
+    pub description: Option<String>,
+}
+
+impl From<&StoredCredential> for CredentialSummary {
+    fn from(c: &StoredCredential) -> Self {
+        Self {
+            id: c.id.clone(),
+            registry: c.registry.clone(),
+            username: c.username.clone(),
+            description: c.description.clone(),
+        }
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct CredentialInput {
+    pub registry: String,
+    pub username: String,
+    // Optional on update, where the stored password is kept
+    #[serde(default)]
+    pub password: Option<String>,
+    #[serde(default)]
+    pub description: Option<String>,
+}
+
+// On-disk envelope: the credential list as JSON, sealed with AES-256-GCM
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    version: u32,
+    nonce: String,
+    ciphertext: String,
+}
+
+pub struct Vault {
+    path: PathBuf,
+    cipher: Aes256Gcm,
+    entries: RwLock<Vec<StoredCredential>>,
+    // Held from reading the entries to swapping them, so concurrent writes
+    // never start from the same snapshot
+    writes: tokio::sync::Mutex<()>,
+}
+
+impl Vault {
+    // CREDENTIAL_STORE_PATH enables the store; the 32-byte key comes base64-encoded
+    // from CREDENTIAL_STORE_KEY or from the file named by CREDENTIAL_STORE_KEY_FILE.
+    pub async fn from_env() -> anyhow::Result<Option<Self>> {
+        let Some(path) = std::env::var("CREDENTIAL_STORE_PATH").ok().filter(|p| !p.is_empty())
+        else {
+            return Ok(None);
+        };
+
+        let key = match (
+            std::env::var("CREDENTIAL_STORE_KEY").ok().filter(|k| !k.is_empty()),
+            std::env::var("CREDENTIAL_STORE_KEY_FILE").ok().filter(|k| !k.is_empty()),
+        ) {
+            (Some(key), _) => key,
+            (None, Some(file)) => tokio::fs::read_to_string(&file)
+                .await
+                .map_err(|e| anyhow::anyhow!("Failed to read CREDENTIAL_STORE_KEY_FILE: {}", e))?,
+            (None, None) => anyhow::bail!(
+                "CREDENTIAL_STORE_PATH requires CREDENTIAL_STORE_KEY or CREDENTIAL_STORE_KEY_FILE"
+            ),
+        };
+        let key = B64
+            .decode(key.trim())
+            .map_err(|_| anyhow::anyhow!("Credential store key must be base64-encoded"))?;
+        let cipher = Aes256Gcm::new_from_slice(&key)
+            .map_err(|_| anyhow::anyhow!("Credential store key must be 32 bytes long"))?;
+
+        let vault = Self::open(PathBuf::from(path), cipher).await?;
+        Ok(Some(vault))
+    }
+
+    async fn open(path: PathBuf, cipher: Aes256Gcm) -> anyhow::Result<Self> {
+        let entries = match tokio::fs::read(&path).await {
+            Ok(data) => decrypt(&cipher, &data)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        info!(
+            "Credential store {} loaded ({} credentials)",
+            path.display(),
+            entries.len()
+        );
+        Ok(Self {
+            path,
+            cipher,
+            entries: RwLock::new(entries),
+            writes: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    pub fn list(&self) -> Vec<CredentialSummary> {
+        self.entries.read().unwrap().iter().map(Into::into).collect()
+    }
+
+    pub fn get(&self, id: &str) -> Option<CredentialSummary> {
+        self.entries
+            .read()
+            .unwrap()
+            .iter()
+            .find(|c| c.id == id)
+            .map(Into::into)
+    }
+
+    // Credentials referenced by id; they must be bound to the registry being
+    // contacted so a caller cannot send them to a host of their choosing.
+    pub fn by_id(&self, id: &str, registry: &str) -> Result<Credentials, VaultError> {
+        let entries = self.entries.read().unwrap();
+        let entry = entries
+            .iter()
+            .find(|c| c.id == id)
+            .ok_or_else(|| VaultError::NotFound(id.to_string()))?;
+        if normalize_host(&entry.registry) != normalize_host(registry) {
+            return Err(VaultError::RegistryMismatch(id.to_string(), registry.to_string()));
+        }
+        Ok(Credentials {
+            username: entry.username.clone(),
+            password: entry.password.clone(),
+        })
+    }
+
+    pub fn by_registry(&self, registry: &str) -> Option<Credentials> {
+        let registry = normalize_host(registry);
+        self.entries
+            .read()
+            .unwrap()
+            .iter()
+            .find(|c| normalize_host(&c.registry) == registry)
+            .map(|c| Credentials {
+                username: c.username.clone(),
+                password: c.password.clone(),
+            })
+    }
+
+    pub async fn create(&self, input: CredentialInput) -> Result<CredentialSummary, VaultError> {
+        let entry = StoredCredential {
+            id: Uuid::new_v4().to_string(),
+            registry: normalize_host(&input.registry),
+            username: input.username,
+            password: input.password.unwrap_or_default(),
+            description: input.description,
+        };
+        let summary = CredentialSummary::from(&entry);
+        let _write = self.writes.lock().await;
+        let mut entries = self.entries.read().unwrap().clone();
+        entries.push(entry);
+        self.persist(entries).await?;
+        Ok(summary)
+    }
+
+    pub async fn update(
+        &self,
+        id: &str,
+        input: CredentialInput,
+    ) -> Result<CredentialSummary, VaultError> {
+        let _write = self.writes.lock().await;
+        let mut entries = self.entries.read().unwrap().clone();
+        let entry = entries
+            .iter_mut()
+            .find(|c| c.id == id)
+            .ok_or_else(|| VaultError::NotFound(id.to_string()))?;
+        entry.registry = normalize_host(&input.registry);
+        entry.username = input.username;
+        if let Some(password) = input.password {
+            entry.password = password;
+        }
+        entry.description = input.description;
+        let summary = CredentialSummary::from(&*entry);
+        self.persist(entries).await?;
+        Ok(summary)
+    }
+
+    pub async fn delete(&self, id: &str) -> Result<(), VaultError> {
+        let _write = self.writes.lock().await;
+        let mut entries = self.entries.read().unwrap().clone();
+        let before = entries.len();
+        entries.retain(|c| c.id != id);
+        if entries.len() == before {
+            return Err(VaultError::NotFound(id.to_string()));
+        }
+        self.persist(entries).await
+    }
+
+    // Seal and atomically replace the store file, then swap the in-memory copy;
+    // called with `writes` held
+    async fn persist(&self, entries: Vec<StoredCredential>) -> Result<(), VaultError> {
+        let data = encrypt(&self.cipher, &entries)?;
+        write_private(&self.path, &data).await?;
+        *self.entries.write().unwrap() = entries;
+        Ok(())
+    }
+}
+
+fn encrypt(cipher: &Aes256Gcm, entries: &[StoredCredential]) -> Result<Vec<u8>, VaultError> {
+    let plaintext = serde_json::to_vec(entries)?;
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, plaintext.as_ref())
+        .map_err(|e| VaultError::Crypto(e.to_string()))?;
+    Ok(serde_json::to_vec_pretty(&Envelope {
+        version: 1,
+        nonce: B64.encode(nonce),
+        ciphertext: B64.encode(ciphertext),
+    })?)
+}
+
+fn decrypt(cipher: &Aes256Gcm, data: &[u8]) -> Result<Vec<StoredCredential>, VaultError> {
+    let envelope: Envelope = serde_json::from_slice(data)?;
+    let nonce = B64
+        .decode(&envelope.nonce)
+        .map_err(|e| VaultError::Crypto(e.to_string()))?;
+    let ciphertext = B64
+        .decode(&envelope.ciphertext)
+        .map_err(|e| VaultError::Crypto(e.to_string()))?;
+    if nonce.len() != 12 {
+        return Err(VaultError::Crypto("invalid nonce".into()));
+    }
+    let plaintext = cipher
+        .decrypt(Nonce::from_slice(&nonce), ciphertext.as_ref())
+        .map_err(|_| VaultError::Crypto("wrong key or corrupted file".into()))?;
+    Ok(serde_json::from_slice(&plaintext)?)
+}
+
+async fn write_private(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let tmp = path.with_extension(format!("tmp-{}", Uuid::new_v4()));
+    let mut options = tokio::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(&tmp).await?;
+    tokio::io::AsyncWriteExt::write_all(&mut file, data).await?;
+    file.sync_all().await?;
+    drop(file);
+    tokio::fs::rename(&tmp, path).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cipher(byte: u8) -> Aes256Gcm {
+        Aes256Gcm::new_from_slice(&[byte; 32]).unwrap()
+    }
+
+    fn input(registry: &str) -> CredentialInput {
+        CredentialInput {
+            registry: registry.to_string(),
+            username: "robot".to_string(),
+            password: Some("s3cret".to_string()),
+            description: None,
+        }
+    }
+
+    // Flip one bit of a base64 field of the envelope
+    fn tamper(data: &[u8], field: &str) -> Vec<u8> {
+        let mut envelope: serde_json::Value = serde_json::from_slice(data).unwrap();
+        let mut bytes = B64.decode(envelope[field].as_str().unwrap()).unwrap();
+        bytes[0] ^= 1;
+        envelope[field] = B64.encode(bytes).into();
+        serde_json::to_vec(&envelope).unwrap()
+    }
+
+    #[tokio::test]
+    async fn seals_and_reopens() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("credentials.json");
+        let vault = Vault::open(path.clone(), cipher(1)).await.unwrap();
+        let created = vault.create(input("https://GHCR.io/")).await.unwrap();
+        assert_eq!(created.registry, "ghcr.io");
+
+        let data = std::fs::read(&path).unwrap();
+        assert!(!String::from_utf8_lossy(&data).contains("s3cret"));
+
+        let reopened = Vault::open(path, cipher(1)).await.unwrap();
+        let creds = reopened.by_id(&created.id, "ghcr.io").unwrap();
+        assert_eq!((creds.username.as_str(), creds.password.as_str()), ("robot", "s3cret"));
+        assert_eq!(reopened.list().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn concurrent_writes_are_all_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("credentials.json");
+        let vault = Vault::open(path.clone(), cipher(1)).await.unwrap();
+        let kept = vault.create(input("quay.io")).await.unwrap();
+        let removed = vault.create(input("registry.gitlab.com")).await.unwrap();
+
+        let (a, b, updated, deleted) = tokio::join!(
+            vault.create(input("ghcr.io")),
+            vault.create(input("docker.io")),
+            vault.update(&kept.id, CredentialInput {
+                description: Some("mirror".to_string()),
+                ..input("quay.io")
+            }),
+            vault.delete(&removed.id),
+        );
+        let (a, b) = (a.unwrap(), b.unwrap());
+        updated.unwrap();
+        deleted.unwrap();
+
+        let reopened = Vault::open(path, cipher(1)).await.unwrap();
+        for vault in [&vault, &reopened] {
+            let mut ids: Vec<String> = vault.list().into_iter().map(|c| c.id).collect();
+            ids.sort();
+            let mut expected = vec![a.id.clone(), b.id.clone(), kept.id.clone()];
+            expected.sort();
+            assert_eq!(ids, expected);
+            assert_eq!(vault.get(&kept.id).unwrap().description.as_deref(), Some("mirror"));
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_wrong_key_and_tampering() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("credentials.json");
+        let vault = Vault::open(path.clone(), cipher(1)).await.unwrap();
+        vault.create(input("ghcr.io")).await.unwrap();
+        let data = std::fs::read(&path).unwrap();
+
+        assert!(matches!(decrypt(&cipher(2), &data), Err(VaultError::Crypto(_))));
+        assert!(Vault::open(path, cipher(2)).await.is_err());
+        for field in ["ciphertext", "nonce"] {
+            let tampered = tamper(&data, field);
+            assert!(matches!(decrypt(&cipher(1), &tampered), Err(VaultError::Crypto(_))), "{}", field);
+        }
+        assert!(decrypt(&cipher(1), &data).is_ok());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn writes_the_store_owner_only() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("credentials.json");
+        let vault = Vault::open(path.clone(), cipher(1)).await.unwrap();
+        vault.create(input("ghcr.io")).await.unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        // No temporary file left next to the store
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn by_id_is_bound_to_the_registry() {
+        let dir = tempfile::tempdir().unwrap();
+        let vault = Vault::open(dir.path().join("credentials.json"), cipher(1)).await.unwrap();
+        let hub = vault.create(input("docker.io")).await.unwrap();
+        let ghcr = vault.create(input("ghcr.io")).await.unwrap();
+
+        assert!(vault.by_id(&hub.id, "registry-1.docker.io").is_ok());
+        assert!(vault.by_id(&ghcr.id, "https://ghcr.io").is_ok());
+        assert!(matches!(vault.by_id(&ghcr.id, "evil.example"), Err(VaultError::RegistryMismatch(..))));
+        assert!(matches!(vault.by_id(&ghcr.id, "ghcr.io.evil.example"), Err(VaultError::RegistryMismatch(..))));
+        assert!(matches!(vault.by_id(&ghcr.id, "ghcr.io:8443"), Err(VaultError::RegistryMismatch(..))));
+        assert!(matches!(vault.by_id("missing", "ghcr.io"), Err(VaultError::NotFound(_))));
+    }
+}
@@ -0,0 +1,146 @@
+use semver::{Version, VersionReq};
+
+// A version constraint as accepted by the pull and index endpoints:
+// an exact version, a semver range (`^1.2`, `~1.2.3`, `>=2, <3`) or `latest-stable`.
+#[derive(Clone, Debug)]
+pub enum Constraint {
+    Exact(String),
+    LatestStable,
+    Range(VersionReq),
+}
+
+impl Constraint {
+    pub fn parse(input: &str) -> Result<Self, String> {
+        let input = input.trim();
+        if input.is_empty() || input == "latest-stable" {
+            return Ok(Self::LatestStable);
+        }
+        // Exact versions keep being passed through untouched, including any `v` prefix
+        if parse_version(input).is_some() {
+            return Ok(Self::Exact(input.to_string()));
+        }
+        VersionReq::parse(input)
+            .map(Self::Range)
+            .map_err(|e| format!("Invalid version constraint '{}': {}", input, e))
+    }
+
+    fn matches(&self, version: &Version) -> bool {
+        match self {
+            Self::Exact(exact) => parse_version(exact).is_some_and(|v| v == *version),
+            Self::LatestStable => version.pre.is_empty(),
+            // VersionReq only matches prereleases when a comparator names one explicitly
+            Self::Range(req) => req.matches(version),
+        }
+    }
+}
+
+impl std::fmt::Display for Constraint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Exact(v) => write!(f, "{}", v),
+            Self::LatestStable => write!(f, "latest-stable"),
+            Self::Range(req) => write!(f, "{}", req),
+        }
+    }
+}
+
+// Parse a chart version or an image tag, tolerating a leading `v` and Helm's
+// `_` substitution for `+` in OCI tags.
+pub fn parse_version(raw: &str) -> Option<Version> {
+    let raw = raw.strip_prefix('v').unwrap_or(raw);
+    Version::parse(&raw.replace('_', "+")).ok()
+}
+
+// Pick the highest candidate satisfying the constraint and return it as it was
+// spelled upstream (so the tag can be pulled as-is).
+pub fn resolve<'a, I>(constraint: &Constraint, candidates: I) -> Option<&'a str>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    candidates
+        .into_iter()
+        .filter_map(|raw| parse_version(raw).map(|v| (v, raw)))
+        .filter(|(v, _)| constraint.matches(v))
+        .max_by(|(a, _), (b, _)| a.cmp(b))
+        .map(|(_, raw)| raw)
+}
+
+// Keep the tags that are valid semver versions, newest first, spelled the way Helm
+// reports them (OCI tags carry `_` where the chart version has `+`).
+pub fn sorted_versions<'a, I>(tags: I) -> Vec<String>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    let mut versions: Vec<(Version, String)> = tags
+        .into_iter()
+        .filter_map(|tag| parse_version(tag).map(|v| (v, tag.replace('_', "+"))))
+        .collect();
+    versions.sort_by(|(a, _), (b, _)| b.cmp(a));
+    versions.into_iter().map(|(_, tag)| tag).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TAGS: &[&str] = &["1.0.0", "v1.2.0", "1.2.5", "1.3.0-rc.1", "1.10.0", "2.0.0", "2.1.0-beta", "3.0.0_build.7"];
+
+    fn resolve_str(constraint: &str) -> Option<&'static str> {
+        resolve(&Constraint::parse(constraint).unwrap(), TAGS.iter().copied())
+    }
+
+    #[test]
+    fn latest_stable_skips_prereleases() {
+        assert_eq!(resolve_str("latest-stable"), Some("3.0.0_build.7"));
+        assert_eq!(resolve_str(""), Some("3.0.0_build.7"));
+        let tags = ["1.0.0", "1.1.0-rc.1", "2.0.0-alpha"];
+        assert_eq!(resolve(&Constraint::LatestStable, tags), Some("1.0.0"));
+        assert_eq!(resolve(&Constraint::LatestStable, ["1.0.0-rc.1"]), None);
+    }
+
+    #[test]
+    fn resolves_ranges() {
+        let cases = [
+            ("^1.2", Some("1.10.0")),
+            ("^1.2.3", Some("1.10.0")),
+            ("~1.2", Some("1.2.5")),
+            ("~1.2.0", Some("1.2.5")),
+            (">=1.0, <2", Some("1.10.0")),
+            (">=2, <3", Some("2.0.0")),
+            // Prereleases only match when the comparator names one
+            (">=2.1.0-alpha, <3", Some("2.1.0-beta")),
+            ("^9", None),
+        ];
+        for (constraint, expected) in cases {
+            assert_eq!(resolve_str(constraint), expected, "{}", constraint);
+        }
+    }
+
+    #[test]
+    fn exact_versions_keep_their_spelling() {
+        assert!(matches!(Constraint::parse("v1.2.0"), Ok(Constraint::Exact(v)) if v == "v1.2.0"));
+        assert_eq!(resolve_str("v1.2.0"), Some("v1.2.0"));
+        assert_eq!(resolve_str("1.2.0"), Some("v1.2.0"));
+        assert_eq!(resolve_str("3.0.0+build.7"), Some("3.0.0_build.7"));
+        assert_eq!(resolve_str("1.3.0-rc.1"), Some("1.3.0-rc.1"));
+        assert_eq!(resolve_str("4.0.0"), None);
+        assert!(Constraint::parse("not a version").is_err());
+    }
+
+    #[test]
+    fn parses_prefix_and_build_metadata() {
+        assert_eq!(parse_version("v1.2.3"), Version::parse("1.2.3").ok());
+        assert_eq!(parse_version("1.2.3_build.7"), Version::parse("1.2.3+build.7").ok());
+        assert_eq!(parse_version("latest"), None);
+        assert_eq!(parse_version("1.2"), None);
+    }
+
+    #[test]
+    fn sorts_newest_first() {
+        let tags = ["1.2.0", "latest", "1.10.0", "v1.9.0", "2.0.0-rc.1", "2.0.0", "1.10.0_build.1", "sha256-abc.sig"];
+        assert_eq!(
+            sorted_versions(tags),
+            ["2.0.0", "2.0.0-rc.1", "1.10.0+build.1", "1.10.0", "v1.9.0", "1.2.0"]
+        );
+    }
+}
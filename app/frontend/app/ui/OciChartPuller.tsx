@@ -19,6 +19,38 @@ export default function OciChartPuller() {
   const [password, setPassword] = useState('');
   const [showAuth, setShowAuth] = useState(false);
   const [state, setState] = useState<DownloadState>({ loading: false, error: '' });
+  const [versions, setVersions] = useState<string[]>([]);
+  const [latest, setLatest] = useState('');
+
+  // Offer the published versions as suggestions once a reference is entered
+  const loadVersions = async () => {
+    setVersions([]);
+    setLatest('');
+    if (!reference.trim()) {
+      return;
+    }
+
+    // Credentials go in the body: the backend refuses them in query strings
+    // once its credential store is enabled
+    const payload = {
+      ref: reference.trim(),
+      ...(username.trim() && password.trim() && { username: username.trim(), password: password.trim() }),
+    };
+
+    try {
+      const res = await fetch('/api/chartVersions', {
+        method: 'POST',
+        headers: { 'Content-Type': 'application/json' },
+        body: JSON.stringify(payload),
+      });
+      if (!res.ok) {
+        return;
+      }
+      const data = await res.json();
+      setVersions(data.versions || []);
+      setLatest(data.latest || '');
+    } catch {}
+  };
 
   const handlePullChart = async () => {
     if (!reference.trim()) {
@@ -47,6 +79,7 @@ export default function OciChartPuller() {
         try {
           const text = await res.text();
           errorMsg = text || errorMsg;
+          errorMsg = JSON.parse(text).message || errorMsg;
         } catch {}
         throw new Error(errorMsg);
       }
@@ -110,6 +143,7 @@ export default function OciChartPuller() {
                 type="text"
                 value={reference}
                 onChange={(e) => setReference(e.target.value)}
+                onBlur={loadVersions}
                 onKeyDown={handleKeyDown}
                 placeholder="ghcr.io/prometheus-community/charts/kube-prometheus-stack"
                 className="sketchy-input w-full"
@@ -129,9 +163,15 @@ export default function OciChartPuller() {
                 value={version}
                 onChange={(e) => setVersion(e.target.value)}
                 onKeyDown={handleKeyDown}
-                placeholder="79.5.0"
+                placeholder={latest || '79.5.0'}
+                list="oci-chart-versions"
                 className="sketchy-input w-full"
               />
+              <datalist id="oci-chart-versions">
+                {versions.map((v) => (
+                  <option key={v} value={v} />
+                ))}
+              </datalist>
               <p className="text-xs text-excalidraw-slate opacity-70 mt-2">
                 {t('oci.versionHelp') || 'If not specified, the latest version will be pulled'}
               </p>
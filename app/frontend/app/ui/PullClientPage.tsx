@@ -84,7 +84,10 @@ export default function PullClientPage() {
           if (!response.ok) {
             const errorText = await response.text();
             const statusCode = response.status;
-            const errorMsg = errorText || `HTTP ${statusCode}`;
+            let errorMsg = errorText || `HTTP ${statusCode}`;
+            try {
+              errorMsg = JSON.parse(errorText).message || errorMsg;
+            } catch {}
             console.error('Pull failed:', { statusCode, errorMsg, body: requestBody });
             setError(`Erreur sur ${ref} (${statusCode}): ${errorMsg}`);
             return;
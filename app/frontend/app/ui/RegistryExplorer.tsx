@@ -51,7 +51,7 @@ export default function RegistryExplorer() {
 
       if (!response.ok) {
         const data = await response.json().catch(() => ({}));
-        const errorMsg = data.error || `HTTP ${response.status}`;
+        const errorMsg = data.message || `HTTP ${response.status}`;
         setError(errorMsg);
         return;
       }
@@ -93,7 +93,7 @@ export default function RegistryExplorer() {
 
       if (!response.ok) {
         const data = await response.json().catch(() => ({}));
-        const errorMsg = data.error || `HTTP ${response.status}`;
+        const errorMsg = data.message || `HTTP ${response.status}`;
         setError(errorMsg);
         return;
       }